@@ -1,26 +1,41 @@
 use std::{
+    collections::HashMap,
     error::Error,
     fs::{self},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use chrono::Local;
+use once_cell::unsync::OnceCell;
 use serde::{Deserialize, Serialize};
 use walkdir::{DirEntry, WalkDir};
 
-use crate::{Mapping, HOME_DIR};
+use crate::{home_dir, snapshot::Snapshot, Mapping};
 
 #[derive(Serialize, Deserialize)]
 pub struct Cache {
     /// The mappings that existed after the last deploy
-    pub(super) mappings: Option<Vec<Mapping>>,
+    mappings: Option<Vec<Mapping>>,
+    /// The target directories read during the last deploy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) snapshot: Option<Snapshot>,
+    /// The config used by the last deploy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) config: Option<PathBuf>,
+    /// The commit of the dotfiles repository the last deploy was made from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) commit: Option<String>,
+    /// Position of each mapping by name, built on first lookup
+    #[serde(skip)]
+    index: OnceCell<HashMap<PathBuf, usize>>,
+    /// Whether this cache differs from the one it was created from
+    #[serde(skip)]
+    pub(super) changed: bool,
 }
 
 impl Default for Cache {
     fn default() -> Self {
-        Self {
-            mappings: Some(Vec::new()),
-        }
+        Cache::new(Vec::new())
     }
 }
 
@@ -28,21 +43,54 @@ impl Cache {
     pub fn new(existing: Vec<Mapping>) -> Cache {
         Cache {
             mappings: Some(existing),
+            snapshot: None,
+            config: None,
+            commit: None,
+            index: OnceCell::new(),
+            changed: true,
         }
     }
 
-    pub fn load() -> Result<Cache, Box<dyn Error>> {
-        let cache_home = if let Ok(cache_home) = shellexpand::env("$XDG_CACHE_HOME/george") {
-            PathBuf::from(cache_home.into_owned())
-        } else if let Some(home) = &*HOME_DIR {
-            PathBuf::from(format!("{home}/.cache/george"))
+    /// Whether this cache has to be saved, i.e. the operation creating it changed anything
+    pub fn is_changed(&self) -> bool {
+        self.changed
+    }
+
+    /// Removes and returns all mappings
+    pub(crate) fn take_mappings(&mut self) -> Vec<Mapping> {
+        self.index.take();
+        self.mappings.take().unwrap_or_default()
+    }
+
+    /// Replaces all mappings, keeping the rest of the cache
+    pub(crate) fn set_mappings(&mut self, mappings: Vec<Mapping>) {
+        self.index.take();
+        self.mappings = Some(mappings);
+    }
+
+    /// The directory the caches are stored in, `$XDG_CACHE_HOME/george` or `~/.cache/george`
+    pub fn default_dir() -> Result<PathBuf, Box<dyn Error>> {
+        if let Ok(cache_home) = shellexpand::env("$XDG_CACHE_HOME/george") {
+            Ok(PathBuf::from(cache_home.into_owned()))
+        } else if let Some(home) = home_dir() {
+            Ok(home.join(".cache/george"))
         } else {
             // TODO: Cache error?
-            return Err(
-                "Failed to expand both $HOME and $XDG_CACHE_HOME, cannot find cache".into(),
-            );
-        };
+            Err("Failed to expand both $HOME and $XDG_CACHE_HOME, cannot find cache".into())
+        }
+    }
+
+    /// The directory the caches of the system deploy are stored in
+    pub fn system_dir() -> PathBuf {
+        PathBuf::from("/var/lib/george")
+    }
+
+    pub fn load() -> Result<Cache, Box<dyn Error>> {
+        Cache::load_from(&Cache::default_dir()?)
+    }
 
+    /// Loads the newest cache in `cache_home`
+    pub fn load_from(cache_home: &Path) -> Result<Cache, Box<dyn Error>> {
         let sort = |lhs: &DirEntry, rhs: &DirEntry| -> std::cmp::Ordering {
             lhs.file_name().cmp(rhs.file_name()).reverse()
         };
@@ -60,20 +108,14 @@ impl Cache {
         }
     }
 
-    pub fn save(self) -> Result<(), Box<dyn Error>> {
-        let cache_home = if let Ok(cache_home) = shellexpand::env("$XDG_CACHE_HOME/george") {
-            PathBuf::from(cache_home.into_owned())
-        } else if let Some(home) = &*HOME_DIR {
-            PathBuf::from(format!("{home}/.cache/george"))
-        } else {
-            // TODO: Cache error?
-            return Err(
-                "Failed to expand both $HOME and $XDG_CACHE_HOME, cannot find cache".into(),
-            );
-        };
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        self.save_to(&Cache::default_dir()?)
+    }
 
+    /// Saves this cache as the newest one in `cache_home`
+    pub fn save_to(&self, cache_home: &Path) -> Result<(), Box<dyn Error>> {
         if !cache_home.exists() {
-            fs::create_dir_all(&cache_home)?;
+            fs::create_dir_all(cache_home)?;
         }
 
         let filename = Local::now().to_string();
@@ -83,11 +125,33 @@ impl Cache {
     }
 
     pub fn contains(&self, mapping: &Mapping) -> bool {
-        if let Some(mappings) = &self.mappings {
-            mappings.contains(mapping)
-        } else {
-            false
-        }
+        self.get(mapping).is_some()
+    }
+
+    /// Returns the cached mapping equal to `mapping`, which carries the metadata from when it was created
+    pub fn get(&self, mapping: &Mapping) -> Option<&Mapping> {
+        let index = self.index.get_or_init(|| {
+            let mut index = HashMap::new();
+            for (i, m) in self.mappings().iter().enumerate() {
+                index.entry(m.name().to_owned()).or_insert(i);
+            }
+            index
+        });
+
+        index
+            .get(mapping.name())
+            .map(|&i| &self.mappings()[i])
+            .filter(|m| *m == mapping)
+    }
+
+    /// The path of the config used by the last deploy
+    pub fn config(&self) -> Option<&Path> {
+        self.config.as_deref()
+    }
+
+    /// The commit of the dotfiles repository the last deploy was made from
+    pub fn commit(&self) -> Option<&str> {
+        self.commit.as_deref()
     }
 
     pub fn mappings(&self) -> &[Mapping] {
@@ -106,14 +170,14 @@ mod tests {
     use serial_test::serial;
 
     use super::*;
+    use crate::EnvGuard;
 
     #[test]
     #[serial]
     fn no_cache_no_cache_home() {
-        let cache_home = env::current_dir().unwrap().join("cache");
-        if cache_home.exists() {
-            fs::remove_dir_all(&cache_home).unwrap();
-        }
+        let _env = EnvGuard::new(&["HOME", "XDG_CACHE_HOME"]);
+        let dir = tempfile::tempdir().unwrap();
+        let cache_home = dir.path().join("cache");
 
         env::remove_var("XDG_CACHE_HOME");
         env::remove_var("HOME");
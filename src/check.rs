@@ -0,0 +1,276 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::{self, ConfigError},
+    filesystem::RealFs,
+    permissions::{self, Drift},
+    pretty_path, LinkKind, Mapping,
+};
+
+/// A problem found in the config by `check`
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    /// A line can't be parsed or sets an invalid option
+    Format(ConfigError),
+    /// The target doesn't exist
+    TargetMissing { line_nr: usize, target: PathBuf },
+    /// The same mapping is listed twice
+    Duplicate {
+        line_nr: usize,
+        other_line_nr: usize,
+        name: PathBuf,
+    },
+    /// The same name is mapped to different targets
+    Conflict {
+        line_nr: usize,
+        other_line_nr: usize,
+        name: PathBuf,
+    },
+    /// The name lies inside the name of another mapping
+    Overlap {
+        line_nr: usize,
+        other_line_nr: usize,
+        name: PathBuf,
+    },
+    /// The name lies outside of the allowed roots, the home directory by default
+    OutsideHome { line_nr: usize, name: PathBuf },
+    /// The target lies outside of the dotfiles directory
+    TargetOutsideDotfiles { line_nr: usize, target: PathBuf },
+    /// The link would be created inside the dotfiles directory
+    LinkIntoDotfiles { line_nr: usize, name: PathBuf },
+    /// The permissions differ from the configured ones, a deploy fixes them
+    PermissionDrift { line_nr: usize, drift: Drift },
+}
+
+impl Problem {
+    pub fn line_nr(&self) -> usize {
+        match self {
+            Problem::Format(e) => e.line_nr(),
+            Problem::TargetMissing { line_nr, .. }
+            | Problem::Duplicate { line_nr, .. }
+            | Problem::Conflict { line_nr, .. }
+            | Problem::Overlap { line_nr, .. }
+            | Problem::OutsideHome { line_nr, .. }
+            | Problem::TargetOutsideDotfiles { line_nr, .. }
+            | Problem::LinkIntoDotfiles { line_nr, .. }
+            | Problem::PermissionDrift { line_nr, .. } => *line_nr,
+        }
+    }
+
+    /// Whether this problem makes the config invalid, the others are only warnings
+    pub fn is_error(&self) -> bool {
+        !matches!(
+            self,
+            Problem::Duplicate { .. } | Problem::Overlap { .. } | Problem::PermissionDrift { .. }
+        )
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Format(e) => write!(f, "{e}"),
+            Problem::TargetMissing { line_nr, target } => write!(
+                f,
+                "line {line_nr}: target {} does not exist",
+                pretty_path(target)
+            ),
+            Problem::Duplicate {
+                line_nr,
+                other_line_nr,
+                name,
+            } => write!(
+                f,
+                "line {line_nr}: {} is already mapped on line {other_line_nr}",
+                pretty_path(name)
+            ),
+            Problem::Conflict {
+                line_nr,
+                other_line_nr,
+                name,
+            } => write!(
+                f,
+                "line {line_nr}: {} is mapped to a different target on line {other_line_nr}",
+                pretty_path(name)
+            ),
+            Problem::Overlap {
+                line_nr,
+                other_line_nr,
+                name,
+            } => write!(
+                f,
+                "line {line_nr}: {} lies inside the mapping on line {other_line_nr}, which it takes precedence over",
+                pretty_path(name)
+            ),
+            Problem::OutsideHome { line_nr, name } => write!(
+                f,
+                "line {line_nr}: {} lies outside of the allowed roots",
+                pretty_path(name)
+            ),
+            Problem::TargetOutsideDotfiles { line_nr, target } => write!(
+                f,
+                "line {line_nr}: target {} lies outside of the dotfiles directory",
+                pretty_path(target)
+            ),
+            Problem::LinkIntoDotfiles { line_nr, name } => write!(
+                f,
+                "line {line_nr}: {} would be linked into the dotfiles directory",
+                pretty_path(name)
+            ),
+            Problem::PermissionDrift { line_nr, drift } => write!(f, "line {line_nr}: {drift}"),
+        }
+    }
+}
+
+/// Validates the content of the config at `path` without creating any links, `home` is the
+/// directory all names should lie in unless the config sets allowed roots. The problems are
+/// sorted by line.
+pub fn check(content: &str, path: &Path, home: Option<&Path>) -> Vec<Problem> {
+    let dotfiles = path.parent().unwrap();
+    let mut problems = Vec::new();
+
+    let (config, errors) = config::parse_config(content, path.to_owned());
+    let mappings = config.mappings();
+    let roots = config.allowed_roots(home);
+    // System links are meant to be outside of the home directory
+    let outside = |m: &Mapping| {
+        let name = m.name();
+        !m.options().system && !roots.is_empty() && !roots.iter().any(|r| name.starts_with(r))
+    };
+    problems.extend(errors.into_iter().map(Problem::Format));
+
+    let line_nr = |m: &Mapping| m.origin().unwrap().line;
+    for (i, mapping) in mappings.iter().enumerate() {
+        let (name, target) = (mapping.name().to_owned(), mapping.target().to_owned());
+        let line_nr = line_nr(mapping);
+
+        if let Some(other) = mappings[..i].iter().find(|m| m.name() == mapping.name()) {
+            let other_line_nr = other.origin().unwrap().line;
+            let name = name.clone();
+            problems.push(if other.target() == mapping.target() {
+                Problem::Duplicate {
+                    line_nr,
+                    other_line_nr,
+                    name,
+                }
+            } else {
+                Problem::Conflict {
+                    line_nr,
+                    other_line_nr,
+                    name,
+                }
+            });
+        }
+
+        for drift in permissions::drift(mapping, &RealFs) {
+            problems.push(Problem::PermissionDrift { line_nr, drift });
+        }
+        if mapping.kind() == LinkKind::Directory {
+            if outside(mapping) {
+                problems.push(Problem::OutsideHome { line_nr, name });
+            }
+            continue;
+        }
+        if !target.exists() {
+            let target = target.clone();
+            problems.push(Problem::TargetMissing { line_nr, target });
+        }
+        if outside(mapping) {
+            let name = name.clone();
+            problems.push(Problem::OutsideHome { line_nr, name });
+        }
+        if !target.starts_with(dotfiles) {
+            problems.push(Problem::TargetOutsideDotfiles { line_nr, target });
+        }
+        if name.starts_with(dotfiles) {
+            problems.push(Problem::LinkIntoDotfiles { line_nr, name });
+        }
+    }
+
+    for (outer, inner) in config::overlaps(mappings) {
+        problems.push(Problem::Overlap {
+            line_nr: line_nr(inner),
+            other_line_nr: line_nr(outer),
+            name: inner.name().to_owned(),
+        });
+    }
+
+    problems.sort_by_key(|p| p.line_nr());
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn problems() {
+        let dotfiles = env::current_dir().unwrap();
+        let path = dotfiles.join(".george");
+        let home = Path::new("/home/me");
+        let config = "
+/home/me/.cargo -> src
+foo - bar
+/home/me/.cargo/config.toml -> Cargo.toml
+/etc/hosts -> Cargo.toml
+/home/me/.zshrc -> missing
+/home/me/.zshrc -> Cargo.toml
+/home/me/.vimrc -> /tmp/george-missing-vimrc
+src/link -> Cargo.toml
+/home/me/.cargo/config.toml -> Cargo.toml
+";
+        let problems = check(config, &path, Some(home));
+        let summary: Vec<(usize, bool)> = problems
+            .iter()
+            .map(|p| (p.line_nr(), p.is_error()))
+            .collect();
+
+        assert_eq!(
+            summary,
+            vec![
+                (3, true),
+                (4, false),
+                (5, true),
+                (6, true),
+                (7, true),
+                (8, true),
+                (8, true),
+                (9, true),
+                (9, true),
+                (10, false),
+                (10, false),
+            ]
+        );
+        assert!(matches!(problems[0], Problem::Format(_)));
+        assert!(matches!(
+            problems[1],
+            Problem::Overlap {
+                other_line_nr: 2,
+                ..
+            }
+        ));
+        assert!(matches!(problems[2], Problem::OutsideHome { .. }));
+        assert!(matches!(problems[3], Problem::TargetMissing { .. }));
+        assert!(matches!(
+            problems[4],
+            Problem::Conflict {
+                other_line_nr: 6,
+                ..
+            }
+        ));
+        assert!(matches!(problems[6], Problem::TargetOutsideDotfiles { .. }));
+        assert!(matches!(problems[8], Problem::LinkIntoDotfiles { .. }));
+        assert!(matches!(
+            problems[9],
+            Problem::Duplicate {
+                other_line_nr: 4,
+                ..
+            }
+        ));
+    }
+}
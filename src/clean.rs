@@ -1,83 +1,263 @@
-use std::fs::{self};
-
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use log::{error, info, warn};
 
-use crate::{cache::Cache, pretty_path, Mapping};
+use crate::{
+    cache::Cache,
+    config::Config,
+    filesystem::{Fs, RealFs},
+    hooks::{self, Hooks},
+    parallel, pretty_path,
+    report::Report,
+    secret, LinkKind, Mapping, Selection,
+};
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct CleanOptions {
     rmdir: bool,
+    /// Only remove the selected links
+    selection: Selection,
+    /// The number of links removed in parallel, 0 and 1 remove them one after another
+    jobs: usize,
+    /// The hooks of the config together with the directory they run in
+    hooks: Option<(Hooks, PathBuf)>,
+    /// The filesystem the links are removed from
+    fs: Arc<dyn Fs>,
+    /// Remove the system links instead of the user links
+    system: bool,
+}
+
+impl Default for CleanOptions {
+    fn default() -> Self {
+        CleanOptions {
+            rmdir: false,
+            selection: Selection::default(),
+            jobs: 0,
+            hooks: None,
+            fs: Arc::new(RealFs),
+            system: false,
+        }
+    }
 }
 
 impl CleanOptions {
     pub fn new(rmdir: bool) -> Self {
-        CleanOptions { rmdir }
+        CleanOptions {
+            rmdir,
+            selection: Selection::default(),
+            jobs: parallel::default_jobs(),
+            hooks: None,
+            fs: Arc::new(RealFs),
+            system: false,
+        }
+    }
+
+    pub fn with_selection(mut self, selection: Selection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    pub fn with_jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    /// Runs the clean hooks of `config`
+    pub fn with_hooks(mut self, config: &Config) -> Self {
+        self.hooks = Some((config.hooks().to_owned(), config.dir().to_owned()));
+        self
+    }
+
+    pub fn with_fs(mut self, fs: Arc<dyn Fs>) -> Self {
+        self.fs = fs;
+        self
+    }
+
+    pub fn with_system(mut self, system: bool) -> Self {
+        self.system = system;
+        self
     }
 }
 
-pub fn clean(mut cache: Cache, opt: CleanOptions) -> Cache {
-    let mut not_removed = Vec::new();
-
-    for mapping in cache.mappings.take().unwrap().into_iter() {
-        let Mapping { name, target } = &mapping;
-
-        if let Ok(link_target) = fs::read_link(name) {
-            // If it doesn't exist, we want to remove without checking this as it would panic
-            if link_target.exists() {
-                let link_target = link_target.canonicalize().unwrap();
-                if link_target != target.canonicalize().unwrap() {
-                    warn!(
-                        "{}: {} points to {} now, treating as removed",
-                        mapping,
-                        pretty_path(name),
-                        pretty_path(&link_target)
-                    );
-                    continue;
-                }
-            }
-        } else {
-            warn!(
+/// Removes the selected links and returns the cache with the links that are left together with a
+/// report of the hooks that failed. If the `pre_clean` hook fails, nothing is removed.
+pub fn clean(mut cache: Cache, opt: CleanOptions) -> (Cache, Report) {
+    let mut report = Report::default();
+    if let Some((hooks, dir)) = &opt.hooks {
+        if let Err(failure) = hooks::run("pre_clean", hooks.pre_clean.as_deref(), dir) {
+            report.hooks.push(failure);
+            return (cache, report);
+        }
+    }
+
+    // Links that aren't selected are kept as they are, system links are only removed when asked
+    let (selected, mut not_removed): (Vec<Mapping>, Vec<Mapping>) = cache
+        .take_mappings()
+        .into_iter()
+        .partition(|m| opt.selection.matches(m) && m.options.system == opt.system);
+
+    let fs = opt.fs.as_ref();
+    let outcomes = parallel::map(&selected, opt.jobs, |m| remove(m, fs));
+
+    // Empty parent directories are removed in order once all links are gone
+    let mut dropped = Vec::new();
+    for (mapping, outcome) in selected.into_iter().zip(outcomes) {
+        let Mapping { name, .. } = &mapping;
+        if !matches!(outcome, Removal::Failed) {
+            dropped.extend(mapping.target().parent().map(Path::to_owned));
+        }
+
+        match outcome {
+            Removal::Changed(link_target) => warn!(
+                "{}: {} points to {} now, treating as removed",
+                mapping.with_source(),
+                pretty_path(name),
+                pretty_path(&link_target)
+            ),
+            Removal::Gone => warn!(
                 "{}: {} doesn't exist anymore or is not a symbolic link, treating as removed",
-                mapping,
+                mapping.with_source(),
+                pretty_path(name)
+            ),
+            Removal::Modified => warn!(
+                "{}: {} was modified since it was decrypted, leaving it",
+                mapping.with_source(),
+                pretty_path(name)
+            ),
+            Removal::NotEmpty => warn!(
+                "{}: {} isn't empty anymore, leaving it",
+                mapping.with_source(),
                 pretty_path(name)
-            );
-            continue;
-        };
-
-        if let Ok(()) = fs::remove_file(name) {
-            info!("{}: removed", mapping);
-            if !opt.rmdir {
-                continue;
+            ),
+            Removal::Failed => {
+                error!("{}: failed to remove", mapping.with_source());
+                not_removed.push(mapping);
             }
-
-            let mut cur = name.as_path();
-            while let Some(parent) = cur.parent() {
-                if parent.exists() && parent.read_dir().unwrap().next().is_none() {
-                    if fs::remove_dir(parent).is_ok() {
-                        info!(
-                            "{}: removed empty parent dir {}",
-                            mapping,
-                            pretty_path(parent)
-                        );
-                        cur = parent;
-                    } else {
-                        error!(
-                            "{}: failed to remove empty parent dir {}",
-                            mapping,
-                            pretty_path(parent)
-                        );
-                        break;
-                    }
-                } else {
-                    break;
+            Removal::Removed => {
+                info!("{}: removed", mapping);
+                if opt.rmdir {
+                    remove_empty_parents(&mapping, fs);
                 }
             }
-        } else {
-            error!("{}: failed to remove", mapping);
-            not_removed.push(mapping);
         }
     }
 
-    Cache::new(not_removed)
+    if let Some((hooks, dir)) = &opt.hooks {
+        if let Err(failure) = hooks::run("post_clean", hooks.post_clean.as_deref(), dir) {
+            report.hooks.push(failure);
+        }
+    }
+
+    // The rest of the cache is kept, only the directories of the removed links are read again
+    if let Some(snapshot) = cache.snapshot.as_mut() {
+        for dir in dropped.iter() {
+            snapshot.forget(dir);
+        }
+    }
+    cache.changed |= !dropped.is_empty();
+    cache.set_mappings(not_removed);
+    (cache, report)
+}
+
+enum Removal {
+    Removed,
+    /// The link points somewhere else now
+    Changed(PathBuf),
+    /// The link doesn't exist anymore or was replaced by something else
+    Gone,
+    /// Files were added to a created directory
+    NotEmpty,
+    /// A decrypted secret was modified
+    Modified,
+    Failed,
+}
+
+fn remove(mapping: &Mapping, fs: &dyn Fs) -> Removal {
+    let Mapping { name, target, .. } = mapping;
+
+    match mapping.kind() {
+        LinkKind::Directory => return remove_dir(mapping, fs),
+        LinkKind::Secret => return remove_secret(mapping, fs),
+        _ => (),
+    }
+
+    if let Ok(link_target) = fs.read_link(name) {
+        if mapping.kind() == LinkKind::Replica {
+            // A replica has to have the content of the symbolic link it replicates, if that still exists
+            if mapping.link_content(fs).is_ok_and(|c| c != link_target) {
+                return Removal::Changed(link_target);
+            }
+        } else if let Ok(real) = fs.canonicalize(name) {
+            // A dangling link is removed without checking where it points to
+            if fs.canonicalize(target).is_ok_and(|t| t != real) {
+                return Removal::Changed(real);
+            }
+        }
+    } else {
+        return Removal::Gone;
+    };
+
+    if let Ok(()) = fs.remove_file(name) {
+        Removal::Removed
+    } else {
+        Removal::Failed
+    }
+}
+
+/// Removes the directory of a directory mapping if it's still empty
+fn remove_dir(mapping: &Mapping, fs: &dyn Fs) -> Removal {
+    let name = mapping.name();
+    if !fs.is_dir(name) || fs.is_symlink(name) {
+        return Removal::Gone;
+    }
+
+    match fs.read_dir(name).map(|entries| entries.is_empty()) {
+        Ok(true) if fs.remove_dir(name).is_ok() => Removal::Removed,
+        Ok(false) => Removal::NotEmpty,
+        _ => Removal::Failed,
+    }
+}
+
+/// Securely removes a decrypted secret if it wasn't modified
+fn remove_secret(mapping: &Mapping, fs: &dyn Fs) -> Removal {
+    let name = mapping.name();
+    if !fs.is_file(name) || fs.is_symlink(name) {
+        return Removal::Gone;
+    }
+    if secret::file_hash(name, fs).as_deref() != mapping.hash() {
+        return Removal::Modified;
+    }
+
+    match secret::remove(name, fs) {
+        Ok(()) => Removal::Removed,
+        Err(_) => Removal::Failed,
+    }
+}
+
+fn remove_empty_parents(mapping: &Mapping, fs: &dyn Fs) {
+    let mut cur = mapping.name();
+    while let Some(parent) = cur.parent() {
+        if fs.read_dir(parent).is_ok_and(|entries| entries.is_empty()) {
+            if fs.remove_dir(parent).is_ok() {
+                info!(
+                    "{}: removed empty parent dir {}",
+                    mapping,
+                    pretty_path(parent)
+                );
+                cur = parent;
+            } else {
+                error!(
+                    "{}: failed to remove empty parent dir {}",
+                    mapping.with_source(),
+                    pretty_path(parent)
+                );
+                break;
+            }
+        } else {
+            break;
+        }
+    }
 }
@@ -1,21 +1,176 @@
-use std::{
-    error::Error,
-    fmt::Display,
+use crate::{
+    expand_path, home_dir, hooks::Hooks, permissions, pretty_path, staging::Staging, LinkKind,
+    Mapping, Origin,
 };
-use std::path::PathBuf;
-use std::fs;
-use crate::Mapping;
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
+use std::{error::Error, fmt::Display};
 
 #[derive(Debug, PartialEq)]
 pub struct Config {
     path: PathBuf,
     mappings: Vec<Mapping>,
+    hooks: Hooks,
+    /// The directories links may be created in, set with `allowed_root`
+    allowed_roots: Vec<PathBuf>,
+    /// The command system links are deployed with, set with `sudo`
+    sudo: Option<String>,
+    /// The config of the repository this one was checked out from into a worktree
+    checked_out_from: Option<PathBuf>,
 }
 
 impl Config {
     pub fn mappings(&self) -> &Vec<Mapping> {
         &self.mappings
     }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// The directory containing the config, targets are relative to it and hooks run in it
+    pub fn dir(&self) -> &Path {
+        self.path.parent().unwrap()
+    }
+
+    pub fn hooks(&self) -> &Hooks {
+        &self.hooks
+    }
+
+    /// The command the system deploy runs george with to gain root privileges, `sudo` by default
+    pub fn sudo(&self) -> &str {
+        self.sudo.as_deref().unwrap_or("sudo")
+    }
+
+    /// Marks this config as a revision of the repository config at `source` checked out into a
+    /// worktree. Deploys record `source` as their config, so later ones use the repository again.
+    pub fn checked_out(mut self, source: PathBuf) -> Config {
+        self.checked_out_from = Some(source);
+        self
+    }
+
+    /// The config of the repository this one was checked out from, see `checked_out`
+    pub fn checked_out_from(&self) -> Option<&Path> {
+        self.checked_out_from.as_deref()
+    }
+
+    /// The config deploys record, the repository config for a checked out revision
+    pub fn source(&self) -> &Path {
+        self.checked_out_from().unwrap_or(&self.path)
+    }
+
+    /// The directories links may be created in, `home` if the config doesn't set any
+    pub fn allowed_roots(&self, home: Option<&Path>) -> Vec<PathBuf> {
+        match self.allowed_roots.is_empty() {
+            true => home.into_iter().map(Path::to_owned).collect(),
+            false => self.allowed_roots.clone(),
+        }
+    }
+
+    /// Returns all pairs of mappings where the name of the second lies inside the name of the
+    /// first, e.g. `~/.config -> config` and `~/.config/nvim/init.lua -> init.lua`. When both
+    /// expand to the same link, the more specific (second) mapping wins.
+    pub fn overlaps(&self) -> Vec<(&Mapping, &Mapping)> {
+        overlaps(&self.mappings)
+    }
+
+    /// Moves all links into the root of `staging`
+    pub fn staged(mut self, staging: &Staging) -> Config {
+        for mapping in self.mappings.iter_mut() {
+            staging.rebase(mapping);
+        }
+        self.allowed_roots = match self.allowed_roots.is_empty() {
+            true => vec![staging.home()],
+            false => self.allowed_roots.iter().map(|r| staging.name(r)).collect(),
+        };
+        self
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    /// The config file can't be read, e.g. because it doesn't exist or isn't valid UTF-8
+    Read {
+        path: PathBuf,
+        kind: io::ErrorKind,
+    },
+    Format(ConfigFormatError),
+    /// The same name is mapped to different targets on two lines
+    Conflict {
+        name: PathBuf,
+        line_nr: usize,
+        other_line_nr: usize,
+    },
+    /// An option or setting is unknown or has an invalid value
+    Option {
+        line_nr: usize,
+        reason: String,
+    },
+    /// A path uses an environment variable that isn't set and has no default
+    UndefinedVariable {
+        line_nr: usize,
+        name: String,
+    },
+}
+
+impl ConfigError {
+    /// The line the error is on, 0 if it concerns the whole file
+    pub fn line_nr(&self) -> usize {
+        match self {
+            ConfigError::Read { .. } => 0,
+            ConfigError::Format(e) => e.line_nr(),
+            ConfigError::Conflict { line_nr, .. }
+            | ConfigError::Option { line_nr, .. }
+            | ConfigError::UndefinedVariable { line_nr, .. } => *line_nr,
+        }
+    }
+}
+
+impl From<ConfigFormatError> for ConfigError {
+    fn from(value: ConfigFormatError) -> Self {
+        ConfigError::Format(value)
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read { path, kind } => {
+                write!(f, "Failed to read config {}: {}", pretty_path(path), kind)
+            }
+            ConfigError::Format(e) => write!(f, "{e}"),
+            ConfigError::Conflict {
+                name,
+                line_nr,
+                other_line_nr,
+            } => write!(
+                f,
+                "Config conflict: {} is mapped to different targets on line {} and line {}",
+                pretty_path(name),
+                other_line_nr,
+                line_nr
+            ),
+            ConfigError::Option { line_nr, reason } => {
+                write!(f, "Config error on line {line_nr}: {reason}")
+            }
+            ConfigError::UndefinedVariable { line_nr, name } => write!(
+                f,
+                "Config error on line {line_nr}: ${name} isn't set, use ${{{name}:-default}} to fall back to a default"
+            ),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Format(e) => Some(e),
+            ConfigError::Read { .. }
+            | ConfigError::Conflict { .. }
+            | ConfigError::Option { .. }
+            | ConfigError::UndefinedVariable { .. } => None,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -31,6 +186,10 @@ impl ConfigFormatError {
             line_nr,
         }
     }
+
+    pub fn line_nr(&self) -> usize {
+        self.line_nr
+    }
 }
 
 impl Display for ConfigFormatError {
@@ -58,34 +217,376 @@ impl Error for ConfigFormatError {
 }
 
 impl Config {
-    pub fn build(path: PathBuf) -> Result<Config, ConfigFormatError> {
-        // TODO: Anyhow
-        let content = fs::read_to_string(&path).unwrap();
-        let config_dir = path.parent().unwrap();
+    pub fn build(path: PathBuf) -> Result<Config, ConfigError> {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) => {
+                return Err(ConfigError::Read {
+                    path,
+                    kind: e.kind(),
+                })
+            }
+        };
+        Config::parse(&content, path)
+    }
+
+    /// Parses the content of the config file at `path`, targets are relative to its directory
+    pub fn parse(content: &str, path: PathBuf) -> Result<Config, ConfigError> {
+        let (parsed, mut errors) = parse_config(content, path);
+        if !errors.is_empty() {
+            return Err(errors.remove(0));
+        }
 
         let mut mappings = Vec::new();
-        for (i, line) in content.lines().filter(|l| !l.is_empty()).enumerate() {
-            let mapping: Vec<&str> = line.split("->").collect();
-            if mapping.len() != 2 {
-                // TODO: use anyhow?
-                return Err(ConfigFormatError::new(line, i + 1));
+        for mapping in parsed.mappings {
+            if let Some(other) = find_conflict(&mappings, &mapping) {
+                return Err(ConfigError::Conflict {
+                    name: mapping.name().to_owned(),
+                    line_nr: mapping.origin().unwrap().line,
+                    other_line_nr: other.origin().unwrap().line,
+                });
+            }
+            mappings.push(mapping);
+        }
+
+        Ok(Config { mappings, ..parsed })
+    }
+}
+
+/// Parses the config at `path`, collecting every error instead of stopping at the first. The
+/// errors are sorted by line and conflicts aren't checked.
+///
+/// Indented `key = value` lines set an option of the mapping above them, unindented ones are
+/// settings like hooks, e.g.
+///
+/// ```text
+/// post_deploy = fc-cache
+/// allowed_root = ~
+/// allowed_root = /etc/xdg
+/// sudo = doas
+/// decrypt = age --decrypt -i ~/.age/key.txt
+/// ~/.local/state/foo ->
+///     type = dir
+///     mode = 700
+/// ~/.netrc -> netrc.age
+///     type = secret
+/// /etc/udev/rules.d/70-keyboard.rules -> udev/keyboard.rules
+///     system = true
+/// ```
+pub(crate) fn parse_config(content: &str, path: PathBuf) -> (Config, Vec<ConfigError>) {
+    let mut hooks = Hooks::default();
+    // The decrypt command of secrets that don't set their own
+    let mut decrypt = None;
+    let mut allowed_roots = Vec::new();
+    let mut sudo = None;
+    let mut mappings: Vec<Mapping> = Vec::new();
+    let mut errors: Vec<ConfigError> = Vec::new();
+    // The options of a mapping that couldn't be parsed are ignored
+    let mut skip_options = false;
+
+    for (i, line) in lines(content) {
+        let line_nr = i + 1;
+        let Some((key, value)) = parse_option(line) else {
+            match parse_line(line, line_nr, &path) {
+                Ok(mapping) => mappings.push(mapping),
+                Err(e) => errors.push(e),
+            }
+            skip_options = errors.last().is_some_and(|e| e.line_nr() == line_nr);
+            continue;
+        };
+
+        let result = if !line.starts_with(char::is_whitespace) {
+            match key {
+                "decrypt" => {
+                    decrypt = Some(value.to_owned());
+                    Ok(())
+                }
+                "sudo" => {
+                    sudo = Some(value.to_owned());
+                    Ok(())
+                }
+                "allowed_root" => {
+                    match expand_vars(value) {
+                        Ok(root) => allowed_roots.push(expand_path(root)),
+                        Err(name) => errors.push(ConfigError::UndefinedVariable { line_nr, name }),
+                    }
+                    Ok(())
+                }
+                _ if hooks.set(key, value) => Ok(()),
+                _ => Err(format!("unknown setting '{key}'")),
             }
+        } else if skip_options {
+            Ok(())
+        } else if let Some(mapping) = mappings.last_mut() {
+            set_option(mapping, key, value)
+        } else {
+            Err(format!("option '{key}' doesn't belong to a mapping"))
+        };
+        if let Err(reason) = result {
+            errors.push(ConfigError::Option { line_nr, reason });
+        }
+    }
+
+    for mapping in mappings.iter_mut() {
+        let line_nr = mapping.origin().unwrap().line;
+        if mapping.kind() != LinkKind::Directory && mapping.target().as_os_str().is_empty() {
+            let reason = "missing target, use 'type = dir' to create a directory".to_owned();
+            errors.push(ConfigError::Option { line_nr, reason });
+        }
+        if mapping.kind() == LinkKind::Secret && mapping.options.decrypt.is_none() {
+            mapping.options.decrypt = decrypt.to_owned();
+            if decrypt.is_none() {
+                let reason = "secret without a 'decrypt' command".to_owned();
+                errors.push(ConfigError::Option { line_nr, reason });
+            }
+        }
+    }
+
+    errors.sort_by_key(|e| e.line_nr());
+    let config = Config {
+        path,
+        mappings,
+        hooks,
+        allowed_roots,
+        sudo,
+        checked_out_from: None,
+    };
+    (config, errors)
+}
+
+/// The non-empty lines of a config together with their index, lines starting with `#` are
+/// comments
+pub(crate) fn lines(content: &str) -> impl Iterator<Item = (usize, &str)> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.is_empty() && !l.trim_start().starts_with('#'))
+}
+
+/// No config was found in any of the searched locations
+#[derive(Debug, PartialEq)]
+pub struct NotFound {
+    pub searched: Vec<PathBuf>,
+}
+
+impl Display for NotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to find a config, searched:")?;
+        for path in self.searched.iter() {
+            write!(f, "\n  {}", pretty_path(path))?;
+        }
+        write!(f, "\nPass one with --config or create one with george init")
+    }
+}
+
+impl Error for NotFound {}
+
+/// Finds the config in this order: `$GEORGE_CONFIG`, a `.george` file in `dir` or one of its
+/// ancestors, the registered config and the config of the last deploy returned by `last`
+pub fn find(dir: &Path, last: impl FnOnce() -> Option<PathBuf>) -> Result<PathBuf, NotFound> {
+    let mut searched = Vec::new();
+
+    // Links are resolved, as targets are relative to the directory containing the actual config
+    if let Some(path) = env::var_os("GEORGE_CONFIG").map(expand_path) {
+        if let Ok(path) = path.canonicalize() {
+            return Ok(path);
+        }
+        searched.push(path);
+    }
+
+    for dir in dir.ancestors() {
+        let path = dir.join(".george");
+        if path.exists() {
+            return Ok(path);
+        }
+        searched.push(path);
+    }
+
+    for path in registered_path().into_iter().chain(last()) {
+        if let Ok(path) = path.canonicalize() {
+            return Ok(path);
+        }
+        searched.push(path);
+    }
+
+    Err(NotFound { searched })
+}
+
+/// Where `george init` registers the config of a dotfiles repo, so that it's found from any
+/// directory: `$XDG_CONFIG_HOME/george/config` or `~/.config/george/config`
+pub fn registered_path() -> Option<PathBuf> {
+    if let Ok(config_home) = shellexpand::env("$XDG_CONFIG_HOME/george/config") {
+        Some(PathBuf::from(config_home.into_owned()))
+    } else {
+        home_dir().map(|home| home.join(".config/george/config"))
+    }
+}
+
+/// Parses line `line_nr` of the config at `path`
+pub(crate) fn parse_line(line: &str, line_nr: usize, path: &Path) -> Result<Mapping, ConfigError> {
+    let config_dir = path.parent().unwrap();
+
+    let mapping: Vec<&str> = line.split("->").collect();
+    if mapping.len() != 2 {
+        // TODO: use anyhow?
+        return Err(ConfigFormatError::new(line, line_nr).into());
+    }
+
+    let expand = |path: &str| {
+        expand_vars(path).map_err(|name| ConfigError::UndefinedVariable { line_nr, name })
+    };
+    let name = expand(mapping[0].trim())?;
+    let target = expand(mapping[1].trim())?;
+
+    // An empty target is only allowed for directory mappings, which is checked once their
+    // options are known
+    let mut mapping = Mapping::new(&name, "");
+    mapping.target = match target.as_str() {
+        "" => PathBuf::new(),
+        target => expand_path(config_dir.join(target)),
+    };
+    let origin = Origin {
+        config: path.to_owned(),
+        line: line_nr,
+        name: mapping.name().to_owned(),
+        target: mapping.target().to_owned(),
+    };
+    Ok(mapping.with_origin(Some(origin)))
+}
+
+/// Expands environment variables like `$XDG_CONFIG_HOME` or `${XDG_DATA_HOME:-~/.local/share}`
+/// and a leading `~`, returns the name of an undefined variable otherwise
+fn expand_vars(path: &str) -> Result<String, String> {
+    let expanded = shellexpand::env(path).map_err(|e| e.var_name)?;
+    Ok(shellexpand::tilde(&expanded).into_owned())
+}
 
-            let name = mapping[0].trim();
-            let target = mapping[1].trim();
-            let target = format!("{}/{}", config_dir.to_str().unwrap(), target);
+/// Splits an option line like `mode = 700` into its key and value
+fn parse_option(line: &str) -> Option<(&str, &str)> {
+    let (key, value) = line.split_once('=')?;
+    let key = key.trim();
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_lowercase() || c == '_') {
+        return None;
+    }
+    Some((key, value.trim()))
+}
 
-            mappings.push(Mapping::new(name, &target));
+/// Sets the option `key` of `mapping`, returns why it couldn't be set otherwise
+fn set_option(mapping: &mut Mapping, key: &str, value: &str) -> Result<(), String> {
+    match key {
+        "type" => match value {
+            "dir" if mapping.target.as_os_str().is_empty() => {
+                mapping.kind = LinkKind::Directory;
+                Ok(())
+            }
+            "dir" => Err("a directory mapping can't have a target".to_owned()),
+            "secret" => {
+                mapping.kind = LinkKind::Secret;
+                Ok(())
+            }
+            "link" => Ok(()),
+            _ => Err(format!(
+                "unknown type '{value}', expected dir, secret or link"
+            )),
+        },
+        "mode" => {
+            mapping.options.mode = Some(parse_mode(value)?);
+            Ok(())
+        }
+        "dir_mode" => {
+            mapping.options.dir_mode = Some(parse_mode(value)?);
+            Ok(())
+        }
+        "owner" => {
+            permissions::uid(value).ok_or_else(|| format!("unknown user '{value}'"))?;
+            mapping.options.owner = Some(value.to_owned());
+            Ok(())
+        }
+        "group" => {
+            permissions::gid(value).ok_or_else(|| format!("unknown group '{value}'"))?;
+            mapping.options.group = Some(value.to_owned());
+            Ok(())
+        }
+        "special" => {
+            mapping.options.special = value.parse().map_err(|_| {
+                format!("invalid value '{value}' for special, expected true or false")
+            })?;
+            Ok(())
         }
+        "system" => {
+            mapping.options.system = value.parse().map_err(|_| {
+                format!("invalid value '{value}' for system, expected true or false")
+            })?;
+            Ok(())
+        }
+        "decrypt" => {
+            mapping.options.decrypt = Some(value.to_owned());
+            Ok(())
+        }
+        "on_change" => {
+            mapping.options.on_change = Some(value.to_owned());
+            Ok(())
+        }
+        _ => Err(format!("unknown option '{key}'")),
+    }
+}
 
-        let config = Config { path, mappings };
-        Ok(config)
+/// Parses an octal mode like `700` or `0644`
+fn parse_mode(value: &str) -> Result<u32, String> {
+    u32::from_str_radix(value, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("invalid mode '{value}', expected octal like 700"))
+}
+
+pub(crate) fn overlaps(mappings: &[Mapping]) -> Vec<(&Mapping, &Mapping)> {
+    let mut overlaps = Vec::new();
+    for outer in mappings.iter() {
+        for inner in mappings.iter() {
+            if inner.name() != outer.name() && inner.name().starts_with(outer.name()) {
+                overlaps.push((outer, inner));
+            }
+        }
     }
+    overlaps
+}
+
+/// Finds a mapping with the same name as `mapping` but a different target
+fn find_conflict<'a>(mappings: &'a [Mapping], mapping: &Mapping) -> Option<&'a Mapping> {
+    let same_name = |m: &&Mapping| m.name() == mapping.name();
+    let other_target = |m: &&Mapping| m.target() != mapping.target();
+    mappings.iter().filter(same_name).find(other_target)
+}
+
+/// How specific a mapping is, links from more specific mappings take precedence
+pub(crate) fn specificity(mapping: &Mapping) -> usize {
+    mapping
+        .origin()
+        .map_or(mapping.name(), |o| o.name.as_path())
+        .components()
+        .count()
 }
 
 #[cfg(test)]
 mod tests {
+    use std::env;
+
+    use serial_test::serial;
+
     use super::*;
+    use crate::EnvGuard;
+
+    fn config_path() -> PathBuf {
+        env::current_dir().unwrap().join(".george")
+    }
+
+    #[test]
+    fn missing_config() {
+        let path = env::current_dir().unwrap().join("missing/.george");
+        let result = Config::build(path.clone());
+        let kind = io::ErrorKind::NotFound;
+        assert_eq!(result, Err(ConfigError::Read { path, kind }));
+    }
 
     #[test]
     fn config_format_err() {
@@ -94,11 +595,11 @@ foo -> bar
 fox -- tox
 ";
 
-        let result = Config::build(config);
-        let expected = Err(ConfigFormatError {
+        let result = Config::parse(config, config_path());
+        let expected = Err(ConfigError::Format(ConfigFormatError {
             line: "fox -- tox".to_string(),
-            line_nr: 2,
-        });
+            line_nr: 3,
+        }));
         assert_eq!(result, expected);
     }
 
@@ -110,7 +611,7 @@ name -> target
 
 from/here/ -> to/there
 ";
-        let result = Config::build(config);
+        let result = Config::parse(config, config_path());
 
         let mappings = vec![
             Mapping::new("name", "target"),
@@ -118,7 +619,147 @@ from/here/ -> to/there
             Mapping::new("from/here/", "to/there"),
         ];
 
-        let expected = Ok(Config { mappings });
+        let expected = Ok(Config {
+            path: config_path(),
+            mappings,
+            hooks: Hooks::default(),
+            allowed_roots: Vec::new(),
+            sudo: None,
+            checked_out_from: None,
+        });
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn origin_line() {
+        let config = "
+name -> target
+
+~/.config -> my-config
+";
+        let result = Config::parse(config, config_path()).unwrap();
+
+        let lines: Vec<usize> = result
+            .mappings()
+            .iter()
+            .map(|m| m.origin().unwrap().line)
+            .collect();
+        assert_eq!(lines, vec![2, 4]);
+        assert_eq!(result.mappings()[1].origin().unwrap().config, config_path());
+    }
+
+    #[test]
+    fn conflicting_names() {
+        let config = "
+~/.zshrc -> zshrc
+~/.vimrc -> vimrc
+~/.zshrc -> other-zshrc
+";
+        let result = Config::parse(config, config_path());
+        let expected = Err(ConfigError::Conflict {
+            name: Mapping::new("~/.zshrc", "").name().to_owned(),
+            line_nr: 4,
+            other_line_nr: 2,
+        });
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn overlapping_names() {
+        let config = "
+~/.config -> config
+~/.config/nvim/init.lua -> init.lua
+~/.configs -> configs
+";
+        let result = Config::parse(config, config_path()).unwrap();
+        let overlaps: Vec<(usize, usize)> = result
+            .overlaps()
+            .iter()
+            .map(|(outer, inner)| (outer.origin().unwrap().line, inner.origin().unwrap().line))
+            .collect();
+        assert_eq!(overlaps, vec![(2, 3)]);
+    }
+
+    #[test]
+    fn mapping_options() {
+        let config = "
+post_deploy = fc-cache -f
+~/.local/state/foo ->
+    type = dir
+    mode = 0700
+~/.pipe -> pipe
+    special = true
+";
+        let result = Config::parse(config, config_path()).unwrap();
+        let dir = &result.mappings()[0];
+        assert_eq!(dir, &Mapping::directory("~/.local/state/foo"));
+        assert_eq!(dir.options().mode, Some(0o700));
+        assert!(result.mappings()[1].options().special);
+        assert_eq!(result.hooks().post_deploy.as_deref(), Some("fc-cache -f"));
+
+        let config = "
+~/.zshrc -> zshrc
+    mode = 800
+~/.vimrc ->
+editor = vim
+";
+        let (_, errors) = parse_config(config, config_path());
+        let lines: Vec<usize> = errors.iter().map(|e| e.line_nr()).collect();
+        assert_eq!(lines, vec![3, 4, 5]);
+    }
+
+    #[test]
+    #[serial]
+    fn environment_variables() {
+        let _env = EnvGuard::new(&["GEORGE_TEST_CONFIG", "GEORGE_TEST_DATA"]);
+        env::set_var("GEORGE_TEST_CONFIG", "/xdg/config");
+        env::remove_var("GEORGE_TEST_DATA");
+        let config = "
+$GEORGE_TEST_CONFIG/nvim -> nvim
+${GEORGE_TEST_DATA:-~/.local/share}/fonts -> $GEORGE_TEST_CONFIG/fonts
+";
+        let result = Config::parse(config, config_path()).unwrap();
+        assert_eq!(
+            result.mappings(),
+            &vec![
+                Mapping::new("/xdg/config/nvim", "nvim"),
+                Mapping::new("~/.local/share/fonts", "/xdg/config/fonts"),
+            ]
+        );
+
+        let result = Config::parse("~/.fonts -> $GEORGE_TEST_DATA/fonts", config_path());
+        let expected = Err(ConfigError::UndefinedVariable {
+            line_nr: 1,
+            name: "GEORGE_TEST_DATA".to_owned(),
+        });
+        assert_eq!(result, expected);
+        let result = Config::parse("allowed_root = $GEORGE_TEST_DATA", config_path());
         assert_eq!(result, expected);
     }
+
+    #[test]
+    #[serial]
+    fn find_config() {
+        let _env = EnvGuard::new(&["GEORGE_CONFIG", "XDG_CONFIG_HOME"]);
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join(".george"), "").unwrap();
+        env::remove_var("GEORGE_CONFIG");
+        // No config is registered there
+        env::set_var("XDG_CONFIG_HOME", dir.join("xdg"));
+
+        let found = find(&dir.join("a/b"), || None);
+        assert_eq!(found, Ok(dir.join(".george")));
+
+        // The config of the last deploy is only used if none is found otherwise
+        let last = dir.join(".george");
+        let found = find(Path::new("/"), || Some(last.clone()));
+        assert_eq!(found, Ok(last.clone()));
+
+        fs::remove_file(&last).unwrap();
+        let err = find(&dir.join("a/b"), || Some(last.clone())).unwrap_err();
+        assert_eq!(err.searched[0], dir.join("a/b/.george"));
+        assert_eq!(err.searched.last(), Some(&last));
+    }
 }
@@ -1,380 +1,1503 @@
-use std::{collections::HashSet, fs, os::unix::fs::symlink};
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
 
+use chrono::Local;
 use log::{error, info, warn};
-use walkdir::WalkDir;
 
 use crate::{
     cache::Cache,
     clean::{self, CleanOptions},
-    config::Config,
-    pretty_path, Mapping,
+    config::{specificity, Config},
+    expand_path,
+    filesystem::{FileKind, Fs, RealFs},
+    git, home_dir,
+    hooks::{self, Hooks},
+    parallel, permissions, pretty_path,
+    report::{Report, SkipReason},
+    secret,
+    snapshot::{Snapshot, SymlinkPolicy},
+    LinkKind, Mapping, Selection,
 };
 
-#[derive(Debug, Default)]
+/// What happens to an existing file that is in the way of a new link
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Rename the file by appending `.backup` and create the link
+    #[default]
+    Backup,
+    /// Keep the file and don't create the link
+    Skip,
+    /// Ask for every file whether to back it up or keep it
+    Ask,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "backup" => Ok(ConflictPolicy::Backup),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "ask" => Ok(ConflictPolicy::Ask),
+            _ => Err(format!(
+                "unknown conflict policy '{s}', expected backup, skip or ask"
+            )),
+        }
+    }
+}
+
+impl Display for ConflictPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConflictPolicy::Backup => write!(f, "backup"),
+            ConflictPolicy::Skip => write!(f, "skip"),
+            ConflictPolicy::Ask => write!(f, "ask"),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct DeployOptions {
     rmdir: bool,
+    /// Only deploy the selected links
+    selection: Selection,
+    /// Skip reading target directories that are unchanged since the last deploy
+    snapshot: bool,
+    /// The number of links created in parallel, 0 and 1 create them one after another
+    jobs: usize,
+    /// How symbolic links inside the target directories are expanded
+    symlinks: SymlinkPolicy,
+    /// The filesystem the links are created in
+    fs: Arc<dyn Fs>,
+    /// Create links outside of the allowed roots of the config as well
+    outside_home: bool,
+    /// Deploy the system links instead of the user links
+    system: bool,
+    /// What happens to existing files in the way of new links
+    conflicts: ConflictPolicy,
+    /// Decides whether a file in the way of a link is backed up with `ConflictPolicy::Ask`
+    ask: fn(&Mapping) -> bool,
+}
+
+impl Default for DeployOptions {
+    fn default() -> Self {
+        DeployOptions {
+            rmdir: false,
+            selection: Selection::default(),
+            snapshot: true,
+            jobs: 0,
+            symlinks: SymlinkPolicy::default(),
+            fs: Arc::new(RealFs),
+            outside_home: false,
+            system: false,
+            conflicts: ConflictPolicy::default(),
+            ask: ask_backup,
+        }
+    }
 }
 
 impl DeployOptions {
     pub fn new(rmdir: bool) -> Self {
-        DeployOptions { rmdir }
+        DeployOptions {
+            rmdir,
+            selection: Selection::default(),
+            snapshot: true,
+            jobs: parallel::default_jobs(),
+            symlinks: SymlinkPolicy::default(),
+            fs: Arc::new(RealFs),
+            outside_home: false,
+            system: false,
+            conflicts: ConflictPolicy::default(),
+            ask: ask_backup,
+        }
+    }
+
+    pub fn with_selection(mut self, selection: Selection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    pub fn with_snapshot(mut self, snapshot: bool) -> Self {
+        self.snapshot = snapshot;
+        self
+    }
+
+    pub fn with_jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    pub fn with_symlinks(mut self, symlinks: SymlinkPolicy) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    pub fn with_fs(mut self, fs: Arc<dyn Fs>) -> Self {
+        self.fs = fs;
+        self
+    }
+
+    pub fn with_outside_home(mut self, outside_home: bool) -> Self {
+        self.outside_home = outside_home;
+        self
+    }
+
+    pub fn with_system(mut self, system: bool) -> Self {
+        self.system = system;
+        self
+    }
+
+    pub fn with_conflicts(mut self, conflicts: ConflictPolicy) -> Self {
+        self.conflicts = conflicts;
+        self
+    }
+
+    /// Replaces asking on the terminal with `ask`
+    pub fn with_ask(mut self, ask: fn(&Mapping) -> bool) -> Self {
+        self.ask = ask;
+        self
     }
 }
 
-pub fn deploy(cache: Cache, opt: DeployOptions, config: Config) -> Cache {
-    let expanded: Vec<Mapping> = expand_mappings(&opt, config.mappings())
-        .into_iter()
-        .collect();
+/// Creates the links of `config` and returns the new cache together with a report of the
+/// entries that were skipped and the hooks that failed. If the `pre_deploy` hook fails, nothing
+/// is deployed. A system deploy only creates the links of `system` mappings and doesn't run the
+/// deploy hooks, which are meant for the user.
+pub fn deploy(mut cache: Cache, opt: DeployOptions, config: Config) -> (Cache, Report) {
+    let mut report = Report::default();
+    let fs = opt.fs.as_ref();
+    let no_hooks = Hooks::default();
+    let hooks = match opt.system {
+        true => &no_hooks,
+        false => config.hooks(),
+    };
+    if let Err(failure) = hooks::run("pre_deploy", hooks.pre_deploy.as_deref(), config.dir()) {
+        report.hooks.push(failure);
+        return (cache, report);
+    }
 
-    // Remove all previously created mappings that have become redundant
-    let redundant_mappings: Vec<Mapping> = cache
+    let previous = cache.snapshot.take();
+    let mut snapshot = Snapshot::default();
+    let reuse = previous.as_ref().filter(|_| opt.snapshot);
+    let expanded = links(&config, &opt, reuse, &mut snapshot, &mut report);
+    let snapshot = match previous.clone() {
+        // Directories outside of the selection weren't read and are kept
+        Some(mut previous) if opt.snapshot && !opt.selection.is_empty() => {
+            previous.extend(snapshot);
+            Some(previous)
+        }
+        _ if opt.snapshot => Some(snapshot),
+        previous => previous,
+    };
+    let mut changed = snapshot != previous;
+
+    // Links outside of the selection are carried over untouched
+    let (selected, mut existing): (Vec<Mapping>, Vec<Mapping>) = cache
         .mappings()
         .iter()
-        .filter(|m| !expanded.contains(m))
-        .map(|m| m.to_owned())
+        .cloned()
+        .partition(|m| opt.selection.matches(m));
+
+    // Remove all previously created mappings that have become redundant
+    let expanded_set: HashSet<&Mapping> = expanded.iter().collect();
+    let redundant_mappings: Vec<Mapping> = selected
+        .into_iter()
+        .filter(|m| !expanded_set.contains(m))
         .collect();
+    changed |= !redundant_mappings.is_empty();
+    // The config mappings whose links were created or removed, for their `on_change` hooks
+    let mut changed_origins: HashSet<PathBuf> =
+        redundant_mappings.iter().filter_map(origin_name).collect();
 
     // If we couldn't remove some of the mappings, we have to keep them in the cache
-    let mut existing = clean::clean(Cache::new(redundant_mappings), CleanOptions::new(opt.rmdir))
-        .mappings
-        .take()
-        .unwrap();
-
-    // Create the new mappings
-    for mapping in expanded.into_iter() {
-        let Mapping { name, target } = &mapping;
-
-        if name.parent().is_some_and(|p| !p.exists()) {
-            if let Ok(()) = fs::create_dir_all(name.parent().unwrap()) {
-                info!("{}: created parent directory", mapping);
-            } else {
-                error!(
-                    "{}: failed to create parent directory, won't create link",
-                    mapping
-                );
+    let (mut not_removed, _) = clean::clean(
+        Cache::new(redundant_mappings),
+        CleanOptions::new(opt.rmdir)
+            .with_jobs(opt.jobs)
+            .with_fs(opt.fs.clone())
+            .with_system(opt.system),
+    );
+    existing.extend(not_removed.take_mappings());
+
+    // Links we created that still exist are kept, all others have to be created
+    let mut to_link = Vec::new();
+    // Cached links whose options didn't change, their permissions are trusted with the snapshot
+    let mut unchanged = HashSet::new();
+    for mut mapping in expanded.into_iter() {
+        if let Some(cached) = cache.get(&mapping) {
+            if mapping.kind == LinkKind::Secret
+                && fs.exists(&mapping.name)
+                && secret::is_outdated(&mapping, cached.created(), fs)
+            {
+                // Our own copy is replaced without a backup, a plaintext backup would never be
+                // removed securely. Modified copies are left alone.
+                let ours = secret::file_hash(&mapping.name, fs).as_deref() == cached.hash();
+                let removed = ours && secret::remove(&mapping.name, fs).is_ok();
+                if !removed {
+                    if ours {
+                        error!("{}: failed to remove outdated copy", mapping.with_source());
+                    } else {
+                        error!(
+                            "{}: decrypted copy was modified, won't replace it",
+                            mapping.with_source()
+                        );
+                    }
+                    mapping.created = cached.created;
+                    mapping.hash = cached.hash.to_owned();
+                    existing.push(mapping);
+                    continue;
+                }
+            } else if fs.exists(&mapping.name) {
+                changed |= cached.origin != mapping.origin;
+                if cached.options == mapping.options {
+                    unchanged.insert(mapping.name.clone());
+                }
+                mapping.created = cached.created;
+                mapping.hash = cached.hash.to_owned();
+                existing.push(mapping);
                 continue;
             }
         }
+        to_link.push(mapping);
+    }
+    let (to_link, kept): (Vec<Mapping>, Vec<Mapping>) = to_link.into_iter().partition(|m| {
+        let conflict = m.kind != LinkKind::Directory && fs.symlink_metadata(&m.name).is_ok();
+        !conflict
+            || match opt.conflicts {
+                ConflictPolicy::Backup => true,
+                ConflictPolicy::Skip => false,
+                ConflictPolicy::Ask => (opt.ask)(m),
+            }
+    });
+    for mapping in kept.iter() {
+        report.skip(&mapping.name, SkipReason::Conflict);
+    }
+    changed |= !to_link.is_empty();
 
-        // If link (or file) already exists
-        if name.exists() {
-            // If we created link
-            if cache.contains(&mapping) {
-                existing.push(mapping);
-                continue;
-            } else {
-                let full_filename = name.to_str().unwrap();
-                let mut backup = name.to_str().unwrap().to_owned();
-                backup += ".backup";
-                if fs::rename(&name, &backup).is_ok() {
-                    info!("{}: {} already exists, backing up to {}", mapping, full_filename, backup);
-                } else {
-                    error!("{}: {} already exist and failed to create {}", mapping, full_filename, backup);
-                    continue;
+    // Parent directories are created in order first, so that the links can be created in parallel
+    let failed_dirs = create_parents(&to_link, fs);
+    let (to_link, no_parent): (Vec<Mapping>, Vec<Mapping>) = to_link
+        .into_iter()
+        .partition(|m| m.name.parent().is_none_or(|p| !failed_dirs.contains(p)));
+    for mapping in no_parent.iter() {
+        error!(
+            "{}: failed to create parent directory, won't create link",
+            mapping.with_source()
+        );
+    }
+
+    let outcomes = parallel::map(&to_link, opt.jobs, |m| link(m, fs));
+    for (mut mapping, outcome) in to_link.into_iter().zip(outcomes) {
+        match outcome {
+            LinkOutcome::Created { backup, hash } => {
+                if let Some(backup) = backup {
+                    info!(
+                        "{}: {} already exists, backing up to {}",
+                        mapping,
+                        pretty_path(&mapping.name),
+                        pretty_path(&backup)
+                    );
                 }
+                info!("{}: created mapping", mapping);
+                changed_origins.extend(origin_name(&mapping));
+                mapping.created = Some(Local::now());
+                mapping.hash = hash;
+                existing.push(mapping);
+            }
+            LinkOutcome::BackupFailed(backup) => error!(
+                "{}: {} already exist and failed to create {}",
+                mapping.with_source(),
+                pretty_path(&mapping.name),
+                pretty_path(&backup)
+            ),
+            LinkOutcome::Failed => error!("{}: failed to create mapping", mapping.with_source()),
+            LinkOutcome::DecryptFailed(e) => {
+                error!("{}: failed to decrypt: {}", mapping.with_source(), e)
             }
         }
+    }
 
-        if let Ok(()) = symlink(target, name) {
-            info!("{}: created mapping", mapping);
-            existing.push(mapping);
-        } else {
-            error!("{}: failed to create mapping", mapping);
+    // Without the snapshot, permissions are applied to all links so that changes made since are
+    // undone
+    permissions::apply(
+        existing
+            .iter()
+            .filter(|m| opt.selection.matches(m))
+            .filter(|m| !opt.snapshot || !unchanged.contains(&m.name)),
+        fs,
+    );
+
+    for mapping in config
+        .mappings()
+        .iter()
+        .filter(|m| m.options.system == opt.system)
+    {
+        let command = mapping.options().on_change.as_deref();
+        if command.is_none() || !changed_origins.contains(mapping.name()) {
+            continue;
         }
+        let hook = format!("on_change of {}", mapping);
+        if let Err(failure) = hooks::run(&hook, command, config.dir()) {
+            report.hooks.push(failure);
+        }
+    }
+    if let Err(failure) = hooks::run("post_deploy", hooks.post_deploy.as_deref(), config.dir()) {
+        report.hooks.push(failure);
+    }
+
+    // A checked out revision records the repository config, only the commit is taken from it
+    let config_path = Some(config.source().to_owned());
+    let commit = git::head(config.dir());
+    changed |= cache.config != config_path || cache.commit != commit;
+
+    let mut cache = Cache::new(existing);
+    cache.snapshot = snapshot;
+    cache.config = config_path;
+    cache.commit = commit;
+    cache.changed = changed;
+    (cache, report)
+}
+
+/// How the links of a deploy differ from the cached ones
+#[derive(Debug, Default, PartialEq)]
+pub struct Changes {
+    /// Links that don't exist yet
+    pub added: Vec<Mapping>,
+    /// Cached links that are removed
+    pub removed: Vec<Mapping>,
+    /// Cached links replaced by a link with the same name, together with their replacement
+    pub changed: Vec<(Mapping, Mapping)>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// The links deploying `config` would add, remove or change, without touching anything
+pub fn changes(cache: &Cache, opt: &DeployOptions, config: &Config) -> Changes {
+    let links = links(
+        config,
+        opt,
+        None,
+        &mut Snapshot::default(),
+        &mut Report::default(),
+    );
+    let cached: BTreeMap<&Path, &Mapping> = cache
+        .mappings()
+        .iter()
+        .filter(|m| opt.selection.matches(m) && m.options.system == opt.system)
+        .map(|m| (m.name(), m))
+        .collect();
+
+    let mut changes = Changes::default();
+    let names: HashSet<&Path> = links.iter().map(Mapping::name).collect();
+    for link in links.iter() {
+        match cached.get(link.name()) {
+            None => changes.added.push(link.to_owned()),
+            Some(&old) if old != link => changes.changed.push((old.to_owned(), link.to_owned())),
+            Some(_) => {}
+        }
+    }
+    let removed = cached.into_iter().filter(|(name, _)| !names.contains(name));
+    changes.removed = removed.map(|(_, m)| m.to_owned()).collect();
+    changes
+}
+
+/// Asks on the terminal whether the file in the way of `mapping` should be backed up
+pub fn ask_backup(mapping: &Mapping) -> bool {
+    eprint!(
+        "{} already exists, back it up and link it? [y/N] ",
+        pretty_path(mapping.name())
+    );
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok() && matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// Expands the selected mappings of `config` into the links to create, reading the target
+/// directories into `snapshot` unless they are unchanged in `reuse`. The links are sorted by name.
+fn links(
+    config: &Config,
+    opt: &DeployOptions,
+    reuse: Option<&Snapshot>,
+    snapshot: &mut Snapshot,
+    report: &mut Report,
+) -> Vec<Mapping> {
+    let fs = opt.fs.as_ref();
+    let roots = config.allowed_roots(home_dir().as_deref());
+    let (mappings, outside): (Vec<Mapping>, Vec<Mapping>) = config
+        .mappings()
+        .iter()
+        .filter(|m| m.options.system == opt.system)
+        .flat_map(|m| opt.selection.narrow(m, fs))
+        // System links are meant to be outside of the home directory
+        .partition(|m| {
+            opt.outside_home || opt.system || roots.iter().any(|r| m.name.starts_with(r))
+        });
+    for mapping in outside.iter() {
+        report.skip(&mapping.name, SkipReason::OutsideRoots);
     }
 
-    Cache::new(existing)
+    let mut expanded: Vec<Mapping> =
+        expand_mappings(&mappings, reuse, snapshot, opt.symlinks, report, fs)
+            .into_iter()
+            .filter(|m| opt.selection.matches(m))
+            .collect();
+    // Never back up or replace the dotfiles themselves, not even with --allow-outside-home
+    let config_path = config
+        .path()
+        .canonicalize()
+        .unwrap_or_else(|_| expand_path(config.path()));
+    let targets: HashSet<PathBuf> = expanded.iter().map(|m| m.target.clone()).collect();
+    expanded.retain(|m| {
+        let protected = is_protected(&m.name, &config_path, &targets, fs);
+        if protected {
+            report.skip(&m.name, SkipReason::Protected);
+        }
+        !protected
+    });
+    expanded.sort_by(|lhs, rhs| lhs.name.cmp(&rhs.name));
+    expanded
 }
 
-fn expand_mappings(opt: &DeployOptions, mappings: &[Mapping]) -> HashSet<Mapping> {
-    let mut set = HashSet::new();
+/// The name of the config mapping `mapping` was created from
+fn origin_name(mapping: &Mapping) -> Option<PathBuf> {
+    mapping.origin().map(|o| o.name.to_owned())
+}
 
+/// Creates the missing parent directories of all links in order and returns those that couldn't be created
+fn create_parents(mappings: &[Mapping], fs: &dyn Fs) -> HashSet<PathBuf> {
+    let mut parents: BTreeMap<&Path, &Mapping> = BTreeMap::new();
     for mapping in mappings.iter() {
-        let Mapping { name, target } = mapping;
+        if let Some(parent) = mapping.name.parent() {
+            parents.entry(parent).or_insert(mapping);
+        }
+    }
 
-        // Target has to exist
-        if !target.exists() {
-            warn!(
-                "{}: '{}' does not exist, skipping",
+    let mut failed = HashSet::new();
+    for (parent, mapping) in parents {
+        if fs.exists(parent) {
+            continue;
+        }
+
+        let missing: Vec<&Path> = parent.ancestors().take_while(|p| !fs.exists(p)).collect();
+        if let Ok(()) = fs.create_dir_all(parent) {
+            info!(
+                "{}: created parent directory {}",
                 mapping,
-                pretty_path(target)
+                pretty_path(parent)
             );
+            for dir in missing.into_iter().rev() {
+                permissions::apply_created(dir, mapping, fs);
+            }
+        } else {
+            failed.insert(parent.to_owned());
+        }
+    }
+    failed
+}
+
+enum LinkOutcome {
+    Created {
+        backup: Option<PathBuf>,
+        /// The hash of a decrypted secret
+        hash: Option<String>,
+    },
+    BackupFailed(PathBuf),
+    Failed,
+    DecryptFailed(io::Error),
+}
+
+/// Creates the link, backing up a file not created by us that's in the way
+fn link(mapping: &Mapping, fs: &dyn Fs) -> LinkOutcome {
+    let Mapping { name, .. } = mapping;
+
+    if mapping.kind() == LinkKind::Directory {
+        return create_dir(mapping, fs);
+    }
+
+    let mut backup = None;
+    if fs.exists(name) {
+        let mut path = name.to_owned().into_os_string();
+        path.push(".backup");
+        let path = PathBuf::from(path);
+        if fs.rename(name, &path).is_err() {
+            return LinkOutcome::BackupFailed(path);
+        }
+        backup = Some(path);
+    }
+
+    if mapping.kind() == LinkKind::Secret {
+        return match secret::decrypt(mapping, fs) {
+            Ok(hash) => LinkOutcome::Created {
+                backup,
+                hash: Some(hash),
+            },
+            Err(e) => LinkOutcome::DecryptFailed(e),
+        };
+    }
+
+    let content = match mapping.link_content(fs) {
+        Ok(content) => content,
+        Err(_) => return LinkOutcome::Failed,
+    };
+    if let Ok(()) = fs.symlink(&content, name) {
+        LinkOutcome::Created { backup, hash: None }
+    } else {
+        LinkOutcome::Failed
+    }
+}
+
+/// Creates the directory of a directory mapping with its mode, an existing directory is kept
+fn create_dir(mapping: &Mapping, fs: &dyn Fs) -> LinkOutcome {
+    let Mapping { name, .. } = mapping;
+
+    if !fs.is_dir(name) && fs.create_dir(name).is_err() {
+        return LinkOutcome::Failed;
+    }
+    if let Some(mode) = mapping.options().mode {
+        if fs.set_mode(name, mode).is_err() {
+            return LinkOutcome::Failed;
+        }
+    }
+    LinkOutcome::Created {
+        backup: None,
+        hash: None,
+    }
+}
+
+/// Whether a link named `name` would back up or replace the config at `config`, the dotfiles
+/// directory containing it, one of its parents or one of the `targets` being linked
+fn is_protected(name: &Path, config: &Path, targets: &HashSet<PathBuf>, fs: &dyn Fs) -> bool {
+    let Some(dotfiles) = config.parent() else {
+        return true;
+    };
+    // The links in the parent of the name are resolved, as they would be when linking
+    let real = name
+        .parent()
+        .and_then(|p| Some(fs.canonicalize(p).ok()?.join(name.file_name()?)));
+    [Some(name.to_owned()), real]
+        .into_iter()
+        .flatten()
+        .any(|name| {
+            targets.contains(&name) || {
+                let name = fs.host_path(&name);
+                name == config || dotfiles.starts_with(&name)
+            }
+        })
+}
+
+/// Expands directory mappings into mappings for every contained file. If two mappings expand
+/// to the same link, the link from the more specific mapping (e.g. `~/.config/nvim/init.lua`
+/// over `~/.config`) is kept.
+/// Directories that are unchanged since they were recorded in `previous` aren't read again,
+/// all directories read are recorded in `snapshot`. Entries that can't be linked are added to
+/// `report`.
+fn expand_mappings(
+    mappings: &[Mapping],
+    previous: Option<&Snapshot>,
+    snapshot: &mut Snapshot,
+    symlinks: SymlinkPolicy,
+    report: &mut Report,
+    fs: &dyn Fs,
+) -> HashSet<Mapping> {
+    let mut links = HashMap::new();
+
+    for mapping in mappings.iter() {
+        let Mapping { name, target, .. } = mapping;
+
+        if mapping.kind() == LinkKind::Directory {
+            insert_link(&mut links, mapping.to_owned());
             continue;
         }
 
+        // Target has to exist
+        let Ok(metadata) = fs.metadata(target) else {
+            report.skip(target, SkipReason::TargetMissing);
+            continue;
+        };
+
         // If target is dir, expand all files in dir first
-        if target.is_dir() {
-            let name_base = name.to_str().unwrap();
-            let target_base = target.to_str().unwrap();
-            let make_mapping = |target: walkdir::DirEntry| {
-                let target = target.path().to_str().unwrap();
-                let name = &target.replace(target_base, name_base);
-                Mapping::new(name, target)
+        if metadata.is_dir() {
+            // The entries are relative to the target, so the links end up at the same place
+            // relative to the name
+            let make_mapping = |(path, kind): (PathBuf, LinkKind)| {
+                let mut link = mapping.to_owned();
+                (link.name, link.target) = (name.join(&path), target.join(&path));
+                // The files inside a directory of secrets are secrets as well
+                match (mapping.kind, kind) {
+                    (LinkKind::Secret, LinkKind::Symlink) => link.with_kind(LinkKind::Secret),
+                    _ => link.with_kind(kind),
+                }
             };
 
-            let files = WalkDir::new(target)
+            let files = snapshot
+                .walk(
+                    target,
+                    previous,
+                    symlinks,
+                    mapping.options().special,
+                    report,
+                    fs,
+                )
                 .into_iter()
-                .filter_map(|e| e.ok())
-                .filter(|e| e.path().is_file())
                 .map(make_mapping);
 
             info!("{}: beginning expansion", mapping);
-            set.extend(expand_mappings(opt, &files.collect::<Vec<_>>()));
+            for link in files {
+                info!("{}: expanded", link);
+                insert_link(&mut links, link);
+            }
             continue;
         }
 
-        if target.is_file() {
+        let special = metadata.kind == FileKind::Special;
+        if metadata.is_file() || (special && mapping.options().special) {
             info!("{}: expanded", mapping);
-            set.insert(mapping.to_owned());
-            continue;
+            insert_link(&mut links, mapping.to_owned());
+        } else if special {
+            report.skip(target, SkipReason::Special);
+        } else {
+            report.skip(target, SkipReason::Unhandled);
         }
-
-        warn!(
-            "{}: was not expanded due to not being handled currently",
-            mapping
-        );
     }
 
-    set
+    links.into_values().collect()
+}
+
+fn insert_link(links: &mut HashMap<PathBuf, Mapping>, mapping: Mapping) {
+    match links.entry(mapping.name.clone()) {
+        Entry::Vacant(entry) => {
+            entry.insert(mapping);
+        }
+        Entry::Occupied(mut entry) => {
+            let existing = entry.get();
+            if *existing == mapping {
+                return;
+            }
+
+            if specificity(&mapping) > specificity(existing) {
+                warn!(
+                    "{}: takes precedence over {}",
+                    mapping.with_source(),
+                    existing.with_source()
+                );
+                entry.insert(mapping);
+            } else {
+                warn!(
+                    "{}: takes precedence over {}",
+                    existing.with_source(),
+                    mapping.with_source()
+                );
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use serial_test::serial;
     use std::{
         fs,
+        os::unix::fs::PermissionsExt,
         path::{Path, PathBuf},
         vec,
     };
+    use tempfile::TempDir;
 
     use super::*;
+    use crate::{filesystem::MemoryFs, permissions::Drift};
 
-    const DOTFILE_DIR: &str = "test_dotfiles";
-    const HOME_DIR: &str = "test_~";
+    /// A config at `/dotfiles/.george` whose links are created below `/home`
+    fn memory_config(content: &str) -> Config {
+        let content = format!("{content}\nallowed_root = /home");
+        Config::parse(&content, PathBuf::from("/dotfiles/.george")).unwrap()
+    }
 
-    fn setup() {
-        if Path::new(HOME_DIR).exists() {
-            fs::remove_dir_all(HOME_DIR).unwrap();
+    /// An in-memory filesystem containing the empty `files` below `/dotfiles`
+    fn memory_fs(files: &[&str]) -> Arc<MemoryFs> {
+        let fs = Arc::new(MemoryFs::new());
+        for file in files {
+            fs.add_file(&Path::new("/dotfiles").join(file), b"")
+                .unwrap();
         }
-        fs::create_dir(HOME_DIR).unwrap();
+        fs
+    }
 
-        if Path::new(DOTFILE_DIR).exists() {
-            fs::remove_dir_all(DOTFILE_DIR).unwrap();
+    /// Asserts that the names of `mappings` are links resolving to their targets
+    fn assert_links(fs: &MemoryFs, mappings: &[Mapping]) {
+        for mapping in mappings {
+            assert!(fs.is_symlink(mapping.name()), "{mapping}");
+            assert_eq!(fs.canonicalize(mapping.name()).unwrap(), mapping.target());
         }
-        fs::create_dir(DOTFILE_DIR).unwrap();
+    }
+
+    /// A temporary directory containing empty `dotfiles` and `home` directories, for the tests
+    /// that need a real filesystem
+    fn setup() -> (TempDir, PathBuf, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let dotfiles = dir.path().join("dotfiles");
+        let home = dir.path().join("home");
+        fs::create_dir(&dotfiles).unwrap();
+        fs::create_dir(&home).unwrap();
+        (dir, dotfiles, home)
+    }
+
+    /// A config at `.george` in `dotfiles` whose links are created in the `home` next to it
+    fn config(dotfiles: &Path, content: &str) -> Config {
+        let home = dotfiles.with_file_name("home");
+        let content = format!("{content}\nallowed_root = {}", home.display());
+        Config::parse(&content, dotfiles.join(".george")).unwrap()
     }
 
     #[test]
-    #[serial]
     fn expand() {
-        setup();
-        let nonempty_dir = format!("{DOTFILE_DIR}/config/nvim");
-        fs::create_dir_all(&nonempty_dir).unwrap();
-        let file1 = format!("{nonempty_dir}/init.lua");
-        let file2 = format!("{nonempty_dir}/something.lua");
-        fs::write(&file1, "").unwrap();
-        fs::write(&file2, "").unwrap();
-        fs::create_dir_all(format!("{DOTFILE_DIR}/config/empty")).unwrap();
-
-        let config = Config::build(&format!("{HOME_DIR}/.config -> {DOTFILE_DIR}/config")).unwrap();
-        let result = expand_mappings(&DeployOptions::default(), config.mappings());
+        let fs = memory_fs(&["config/nvim/init.lua", "config/nvim/something.lua"]);
+        fs.create_dir_all(Path::new("/dotfiles/config/empty"))
+            .unwrap();
+
+        let config = memory_config("/home/.config -> config");
+        let result = expand_mappings(
+            config.mappings(),
+            None,
+            &mut Snapshot::default(),
+            SymlinkPolicy::Follow,
+            &mut Report::default(),
+            fs.as_ref(),
+        );
 
         assert!(result.contains(&Mapping::new(
-            &format!("{HOME_DIR}/.config/nvim/init.lua"),
-            &file1
+            "/home/.config/nvim/init.lua",
+            "/dotfiles/config/nvim/init.lua"
         )));
         assert!(result.contains(&Mapping::new(
-            &format!("{HOME_DIR}/.config/nvim/something.lua"),
-            &file2
+            "/home/.config/nvim/something.lua",
+            "/dotfiles/config/nvim/something.lua"
         )));
     }
 
     #[test]
-    #[serial]
     fn fail_link_file_exists() {
-        setup();
-        let target = format!("{DOTFILE_DIR}/.zshrc");
-        fs::write(&target, "").unwrap();
-
-        let name = format!("{HOME_DIR}/.zshrc");
-        fs::write(&name, "").unwrap();
+        let fs = memory_fs(&[".zshrc"]);
+        let name = Path::new("/home/.zshrc");
+        fs.add_file(name, b"").unwrap();
 
-        let config = Config::build(&format!("{name} -> {target}")).unwrap();
-        let result = deploy(Cache::default(), DeployOptions::default(), config);
+        let config = memory_config("/home/.zshrc -> .zshrc");
+        let opt = DeployOptions::default()
+            .with_fs(fs.clone())
+            .with_conflicts(ConflictPolicy::Skip);
+        let (result, _) = deploy(Cache::default(), opt, config);
 
         let expected = vec![];
         assert_eq!(result.mappings(), expected);
-        assert!(!PathBuf::from(name).is_symlink());
+        assert!(!fs.is_symlink(name));
     }
 
     #[test]
-    #[serial]
     fn link_single_file() {
-        setup();
-        let target = format!("{DOTFILE_DIR}/.zshrc");
-        fs::write(&target, "").unwrap();
-
-        let name = format!("{HOME_DIR}/.zshrc");
+        let fs = memory_fs(&[".zshrc"]);
 
-        let config = Config::build(&format!("{name} -> {target}")).unwrap();
-        let result = deploy(Cache::default(), DeployOptions::default(), config);
+        let config = memory_config("/home/.zshrc -> .zshrc");
+        let opt = DeployOptions::default().with_fs(fs.clone());
+        let (result, _) = deploy(Cache::default(), opt, config);
 
-        let expected = vec![Mapping::new(&name, &target)];
+        let expected = vec![Mapping::new("/home/.zshrc", "/dotfiles/.zshrc")];
         assert_eq!(result.mappings(), expected);
-        assert!(PathBuf::from(&name).exists());
-        assert!(PathBuf::from(&name).is_symlink());
-        assert!(PathBuf::from(&name)
-            .canonicalize()
-            .is_ok_and(|p| p == PathBuf::from(&target).canonicalize().unwrap()));
+        assert_links(&fs, &expected);
     }
 
     #[test]
-    #[serial]
     fn link_file_in_nested_dir() {
-        setup();
-        let target = format!("{DOTFILE_DIR}/nvim/init.lua");
-        fs::create_dir(format!("{DOTFILE_DIR}/nvim")).unwrap();
-        fs::write(&target, "").unwrap();
-
-        let name = format!("{HOME_DIR}/.config/nvim/init.lua");
+        let fs = memory_fs(&["nvim/init.lua"]);
 
-        let config = Config::build(&format!("{name} -> {target}")).unwrap();
-        let result = deploy(Cache::default(), DeployOptions::default(), config);
+        let config = memory_config("/home/.config/nvim/init.lua -> nvim/init.lua");
+        let opt = DeployOptions::default().with_fs(fs.clone());
+        let (result, _) = deploy(Cache::default(), opt, config);
 
-        let expected = vec![Mapping::new(&name, &target)];
+        let expected = vec![Mapping::new(
+            "/home/.config/nvim/init.lua",
+            "/dotfiles/nvim/init.lua",
+        )];
         assert_eq!(result.mappings(), expected);
-        assert!(PathBuf::from(&name).exists());
-        assert!(PathBuf::from(&name).is_symlink());
-        assert!(PathBuf::from(&name)
-            .canonicalize()
-            .is_ok_and(|p| p == PathBuf::from(&target).canonicalize().unwrap()));
+        assert_links(&fs, &expected);
     }
 
     #[test]
-    #[serial]
     fn link_empty_dir() {
-        setup();
-        let target = format!("{DOTFILE_DIR}/nvim");
-        fs::create_dir(&target).unwrap();
+        let fs = memory_fs(&[]);
+        fs.create_dir_all(Path::new("/dotfiles/nvim")).unwrap();
 
-        let name = format!("{HOME_DIR}/.config/nvim");
-
-        let config = Config::build(&format!("{name} -> {target}")).unwrap();
-        let result = deploy(Cache::default(), DeployOptions::default(), config);
+        let config = memory_config("/home/.config/nvim -> nvim");
+        let opt = DeployOptions::default().with_fs(fs.clone());
+        let (result, _) = deploy(Cache::default(), opt, config);
 
         let expected = vec![];
         assert_eq!(result.mappings(), expected);
-        assert!(!PathBuf::from(name).exists());
+        assert!(!fs.exists(Path::new("/home/.config/nvim")));
     }
 
     #[test]
-    #[serial]
     fn link_nonempty_dir() {
-        setup();
-        let target = format!("{DOTFILE_DIR}/nvim");
-        fs::create_dir(format!("{DOTFILE_DIR}/nvim")).unwrap();
+        let fs = memory_fs(&["nvim/init.lua"]);
 
-        let init_target = &format!("{DOTFILE_DIR}/nvim/init.lua");
-        fs::write(init_target, "").unwrap();
+        let config = memory_config("/home/.config/nvim -> nvim");
+        let opt = DeployOptions::default().with_fs(fs.clone());
+        let (result, _) = deploy(Cache::default(), opt, config);
 
-        let name = format!("{HOME_DIR}/.config/nvim");
+        let expected = vec![Mapping::new(
+            "/home/.config/nvim/init.lua",
+            "/dotfiles/nvim/init.lua",
+        )];
+        assert_eq!(result.mappings(), expected);
+        assert_links(&fs, &expected);
+    }
 
-        let config = Config::build(&format!("{name} -> {target}")).unwrap();
-        let result = deploy(Cache::default(), DeployOptions::default(), config);
+    #[test]
+    fn link_nonempty_nested_dirs() {
+        let fs = memory_fs(&["nvim/init.lua", "nvim/lua/guy/nested.lua"]);
+
+        let config = memory_config("/home/.config/nvim -> nvim");
+        let opt = DeployOptions::default().with_fs(fs.clone());
+        let (result, _) = deploy(Cache::default(), opt, config);
+
+        let expected = [
+            Mapping::new("/home/.config/nvim/init.lua", "/dotfiles/nvim/init.lua"),
+            Mapping::new(
+                "/home/.config/nvim/lua/guy/nested.lua",
+                "/dotfiles/nvim/lua/guy/nested.lua",
+            ),
+        ];
+        assert!(expected.iter().all(|m| result.contains(m)));
+        assert_links(&fs, &expected);
+    }
 
-        let init_link = &format!("{HOME_DIR}/.config/nvim/init.lua");
+    #[test]
+    fn multiple_mappings() {
+        let fs = memory_fs(&["nvim/init.lua", ".vimrc"]);
 
-        let expected = vec![Mapping::new(init_link, init_target)];
-        assert_eq!(result.mappings(), expected);
-        assert!(PathBuf::from(&init_link).exists());
-        assert!(PathBuf::from(&init_link).is_symlink());
-        assert!(PathBuf::from(&init_link)
-            .canonicalize()
-            .is_ok_and(|p| p == PathBuf::from(&init_target).canonicalize().unwrap()));
+        let config = memory_config(
+            "/home/.config/nvim -> nvim
+            /home/.vimrc -> .vimrc",
+        );
+        let opt = DeployOptions::default().with_fs(fs.clone());
+        let (result, _) = deploy(Cache::default(), opt, config);
+
+        let expected = [
+            Mapping::new("/home/.config/nvim/init.lua", "/dotfiles/nvim/init.lua"),
+            Mapping::new("/home/.vimrc", "/dotfiles/.vimrc"),
+        ];
+        assert!(expected.iter().all(|m| result.contains(m)));
+        assert_links(&fs, &expected);
     }
 
     #[test]
-    #[serial]
-    fn link_nonempty_nested_dirs() {
-        setup();
-        let target = format!("{DOTFILE_DIR}/nvim");
-        fs::create_dir(format!("{DOTFILE_DIR}/nvim")).unwrap();
+    fn clean_single_mapping() {
+        let fs = memory_fs(&["nvim/init.lua", ".vimrc"]);
+
+        let config = memory_config(
+            "/home/.config/nvim -> nvim
+            /home/.vimrc -> .vimrc",
+        );
+        let opt = DeployOptions::default().with_fs(fs.clone());
+        let (cache, _) = deploy(Cache::default(), opt, config);
+        let config_path = cache.config().map(Path::to_owned);
+
+        let selection = Selection::new(vec![], Some(PathBuf::from("/home/.config/nvim")));
+        let opt = CleanOptions::new(true)
+            .with_selection(selection)
+            .with_fs(fs.clone());
+        let (result, _) = clean::clean(cache, opt);
+
+        let vimrc = Mapping::new("/home/.vimrc", "/dotfiles/.vimrc");
+        assert_eq!(result.mappings(), vec![vimrc.clone()]);
+        assert_eq!(result.config(), config_path.as_deref());
+        assert_links(&fs, &[vimrc]);
+        assert!(!fs.exists(Path::new("/home/.config/nvim/init.lua")));
+        assert!(result.mappings()[0].origin().is_some_and(|o| o.line == 2));
+    }
+
+    #[test]
+    fn deploy_selected_path() {
+        let fs = memory_fs(&["config/nvim/init.lua", "config/git/config"]);
+
+        let content = "/home/.config -> config";
+        let opt = || DeployOptions::new(true).with_fs(fs.clone());
+        let (cache, _) = deploy(Cache::default(), opt(), memory_config(content));
+        assert_eq!(cache.mappings().len(), 2);
+
+        // The git link is redundant now, but outside of the selection
+        let git_target = Path::new("/dotfiles/config/git/config");
+        fs.remove_file(git_target).unwrap();
+        fs.add_file(Path::new("/dotfiles/config/nvim/new.lua"), b"")
+            .unwrap();
+
+        let selection = Selection::new(vec![PathBuf::from("/home/.config/nvim")], None);
+        let (result, _) = deploy(
+            cache,
+            opt().with_selection(selection),
+            memory_config(content),
+        );
 
-        let init_target = &format!("{DOTFILE_DIR}/nvim/init.lua");
-        fs::write(init_target, "").unwrap();
+        let git = Mapping::new("/home/.config/git/config", "/dotfiles/config/git/config");
+        let new = Mapping::new(
+            "/home/.config/nvim/new.lua",
+            "/dotfiles/config/nvim/new.lua",
+        );
+        assert_eq!(result.mappings().len(), 3);
+        assert!(result.contains(&git));
+        assert!(result.contains(&new));
+        assert!(fs.is_symlink(git.name()));
+        assert_links(&fs, &[new]);
+    }
 
-        let nested = format!("{DOTFILE_DIR}/nvim/lua/guy");
-        fs::create_dir_all(nested).unwrap();
+    #[test]
+    fn specific_mapping_wins() {
+        let fs = memory_fs(&["config/nvim/init.lua", "init.lua"]);
+
+        let init_link = "/home/.config/nvim/init.lua";
+        for content in [
+            format!("/home/.config -> config\n{init_link} -> init.lua"),
+            format!("{init_link} -> init.lua\n/home/.config -> config"),
+        ] {
+            let result = expand_mappings(
+                memory_config(&content).mappings(),
+                None,
+                &mut Snapshot::default(),
+                SymlinkPolicy::Follow,
+                &mut Report::default(),
+                fs.as_ref(),
+            );
+            assert_eq!(result.len(), 1);
+            assert!(result.contains(&Mapping::new(init_link, "/dotfiles/init.lua")));
+        }
+    }
 
-        let nested_target = format!("{DOTFILE_DIR}/nvim/lua/guy/nested.lua");
-        fs::write(&nested_target, "").unwrap();
+    #[test]
+    fn redeploy_unchanged() {
+        let fs = memory_fs(&["nvim/init.lua"]);
+
+        let content = "/home/.config/nvim -> nvim";
+        let opt = || {
+            DeployOptions::new(true)
+                .with_snapshot(true)
+                .with_fs(fs.clone())
+        };
+        let (cache, _) = deploy(Cache::default(), opt(), memory_config(content));
+        assert!(cache.is_changed());
+
+        let (cache, _) = deploy(cache, opt(), memory_config(content));
+        assert!(!cache.is_changed());
+        assert_eq!(cache.mappings().len(), 1);
+
+        fs.add_file(Path::new("/dotfiles/nvim/new.lua"), b"")
+            .unwrap();
+        let (cache, _) = deploy(cache, opt(), memory_config(content));
+        assert!(cache.is_changed());
+        assert!(cache.contains(&Mapping::new(
+            "/home/.config/nvim/new.lua",
+            "/dotfiles/nvim/new.lua"
+        )));
+    }
 
-        let name = format!("{HOME_DIR}/.config/nvim");
+    #[test]
+    fn parallel_deploy_and_clean() {
+        let files: Vec<String> = (0..100)
+            .map(|i| format!("many/{}/{}", i / 10, i % 10))
+            .collect();
+        let fs = memory_fs(&files.iter().map(String::as_str).collect::<Vec<_>>());
+
+        let config = memory_config("/home/many -> many");
+        let opt = DeployOptions::new(true).with_jobs(4).with_fs(fs.clone());
+        let (result, _) = deploy(Cache::default(), opt, config);
+
+        assert_eq!(result.mappings().len(), 100);
+        let names: Vec<&Path> = result.mappings().iter().map(|m| m.name()).collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(names, sorted);
+        assert!(names.iter().all(|name| fs.is_symlink(name)));
+
+        let opt = CleanOptions::new(true).with_jobs(4).with_fs(fs.clone());
+        let (result, _) = clean::clean(result, opt);
+        assert!(result.mappings().is_empty());
+        assert!(!fs.exists(Path::new("/home/many")));
+    }
 
-        let config = Config::build(&format!("{name} -> {target}")).unwrap();
-        let result = deploy(Cache::default(), DeployOptions::default(), config);
+    #[test]
+    fn in_memory() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.add_file(Path::new("/dotfiles/nvim/init.lua"), b"")
+            .unwrap();
+        fs.add_file(Path::new("/home/.config/nvim/init.lua"), b"old")
+            .unwrap();
+        let content =
+            "allowed_root = /home\n/home/.config/nvim -> nvim\n/home/.cache ->\n    type = dir";
+        let config = Config::parse(content, PathBuf::from("/dotfiles/.george")).unwrap();
+
+        let opt = DeployOptions::new(true).with_fs(fs.clone());
+        let (cache, report) = deploy(Cache::default(), opt, config);
+        assert!(report.is_empty());
+        assert_eq!(cache.mappings().len(), 2);
+        let init = Path::new("/home/.config/nvim/init.lua");
+        assert_eq!(
+            fs.read_link(init).unwrap(),
+            PathBuf::from("/dotfiles/nvim/init.lua")
+        );
+        assert_eq!(fs.read(&init.with_extension("lua.backup")).unwrap(), b"old");
+        assert!(fs.is_dir(Path::new("/home/.cache")));
 
-        let init_link = format!("{HOME_DIR}/.config/nvim/init.lua");
-        let nested_link = format!("{HOME_DIR}/.config/nvim/lua/guy/nested.lua");
+        let (cache, _) = clean::clean(cache, CleanOptions::new(true).with_fs(fs.clone()));
+        assert!(cache.mappings().is_empty());
+        assert!(!fs.exists(Path::new("/home/.cache")));
+        assert!(!fs.is_symlink(init));
+    }
 
-        let init_mapping = Mapping::new(&init_link, init_target);
-        assert!(result.contains(&init_mapping));
-        assert!(PathBuf::from(&init_link).exists());
-        assert!(PathBuf::from(&init_link).is_symlink());
-        assert!(PathBuf::from(&init_link)
-            .canonicalize()
-            .is_ok_and(|p| p == PathBuf::from(&init_target).canonicalize().unwrap()));
+    #[test]
+    fn allowed_roots() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.add_file(Path::new("/home/me/dotfiles/hosts"), b"")
+            .unwrap();
+        let content = "
+allowed_root = /home/me
+/home/me/.hosts -> hosts
+/etc/hosts -> hosts
+/home/me/dotfiles/.george -> hosts
+/home/me/dotfiles -> hosts
+/home/me/dotfiles/hosts -> hosts
+/home/me/dotfiles/notes -> hosts
+";
+        let path = PathBuf::from("/home/me/dotfiles/.george");
+        let skipped = |report: &Report| -> Vec<SkipReason> {
+            report.skipped.iter().map(|s| s.reason.clone()).collect()
+        };
+
+        let opt = DeployOptions::new(true).with_fs(fs.clone());
+        let config = Config::parse(content, path.clone()).unwrap();
+        let (cache, report) = deploy(Cache::default(), opt, config);
+        assert_eq!(cache.mappings().len(), 2);
+        assert_eq!(
+            skipped(&report),
+            vec![
+                SkipReason::OutsideRoots,
+                SkipReason::Protected,
+                SkipReason::Protected,
+                SkipReason::Protected
+            ]
+        );
+        // Only the config, the dotfiles directory with its parents and the targets are protected
+        assert!(fs.is_symlink(Path::new("/home/me/dotfiles/notes")));
+
+        let opt = DeployOptions::new(true)
+            .with_fs(fs.clone())
+            .with_outside_home(true);
+        let config = Config::parse(content, path).unwrap();
+        let (cache, report) = deploy(cache, opt, config);
+        assert_eq!(cache.mappings().len(), 3);
+        assert!(fs.is_symlink(Path::new("/etc/hosts")));
+        assert!(!fs.is_symlink(Path::new("/home/me/dotfiles")));
+        assert!(!fs.is_symlink(Path::new("/home/me/dotfiles/hosts")));
+        assert_eq!(skipped(&report), vec![SkipReason::Protected; 3]);
+    }
 
-        let nested_mapping = Mapping::new(&nested_link, &nested_target);
-        assert!(result.contains(&nested_mapping));
-        assert!(PathBuf::from(&nested_link).exists());
-        assert!(PathBuf::from(&nested_link).is_symlink());
-        assert!(PathBuf::from(&nested_link)
-            .canonicalize()
-            .is_ok_and(|p| p == PathBuf::from(&nested_target).canonicalize().unwrap()));
+    #[test]
+    fn config_in_home() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.add_file(Path::new("/home/me/dotfiles/zshrc"), b"")
+            .unwrap();
+        fs.add_file(Path::new("/home/me/.george"), b"").unwrap();
+        let content = "
+/home/me/.zshrc -> dotfiles/zshrc
+/home/me/.config/zsh -> dotfiles
+/home/me/.george -> dotfiles/zshrc
+/home/me -> dotfiles/zshrc
+";
+        let config = Config::parse(content, PathBuf::from("/home/me/.george")).unwrap();
+        let opt = DeployOptions::new(true)
+            .with_fs(fs.clone())
+            .with_outside_home(true);
+        let (cache, report) = deploy(Cache::default(), opt, config);
+
+        // Links next to the config are fine, only the config and the home directory aren't
+        let zshrc = Mapping::new("/home/me/.zshrc", "/home/me/dotfiles/zshrc");
+        let nested = Mapping::new("/home/me/.config/zsh/zshrc", "/home/me/dotfiles/zshrc");
+        assert_eq!(cache.mappings(), vec![nested.clone(), zshrc.clone()]);
+        assert_links(&fs, &[zshrc, nested]);
+        assert_eq!(report.skipped.len(), 2);
+        assert!(report
+            .skipped
+            .iter()
+            .all(|s| s.reason == SkipReason::Protected));
+        assert_eq!(fs.read(Path::new("/home/me/.george")).unwrap(), b"");
     }
 
     #[test]
-    #[serial]
-    fn multiple_mappings() {
-        setup();
+    fn system_links() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.add_file(Path::new("/dotfiles/zshrc"), b"").unwrap();
+        fs.add_file(Path::new("/dotfiles/hosts"), b"").unwrap();
+        let content = "
+allowed_root = /home
+/home/.zshrc -> zshrc
+/etc/hosts.d/dev -> hosts
+    system = true
+";
+        let config = || Config::parse(content, PathBuf::from("/dotfiles/.george")).unwrap();
+        let hosts = Path::new("/etc/hosts.d/dev");
+
+        let opt = DeployOptions::new(true).with_fs(fs.clone());
+        let (user, report) = deploy(Cache::default(), opt, config());
+        assert!(report.is_empty());
+        assert_eq!(user.mappings().len(), 1);
+        assert!(!fs.exists(hosts));
+
+        let opt = DeployOptions::new(true)
+            .with_fs(fs.clone())
+            .with_system(true);
+        let (system, _) = deploy(Cache::default(), opt, config());
+        assert_eq!(system.mappings().len(), 1);
+        assert!(fs.is_symlink(hosts));
+
+        // A plain clean leaves system links alone, even if they are in its cache
+        let mut mappings = user.mappings().to_vec();
+        mappings.extend(system.mappings().iter().cloned());
+        let opt = CleanOptions::new(true).with_fs(fs.clone());
+        let (left, _) = clean::clean(Cache::new(mappings), opt);
+        assert_eq!(left.mappings(), system.mappings());
+        assert!(fs.is_symlink(hosts));
+        assert!(!fs.exists(Path::new("/home/.zshrc")));
+    }
+
+    #[test]
+    fn conflict_policy() {
+        let fs = Arc::new(MemoryFs::new());
+        for file in [
+            "/dotfiles/zshrc",
+            "/dotfiles/vimrc",
+            "/home/.zshrc",
+            "/home/.vimrc",
+        ] {
+            fs.add_file(Path::new(file), b"").unwrap();
+        }
+        let content = "allowed_root = /home\n/home/.zshrc -> zshrc\n/home/.vimrc -> vimrc";
+        let config = || Config::parse(content, PathBuf::from("/dotfiles/.george")).unwrap();
+        let opt = |conflicts| {
+            DeployOptions::new(true)
+                .with_fs(fs.clone())
+                .with_conflicts(conflicts)
+                .with_ask(|m| m.name().ends_with(".zshrc"))
+        };
+
+        let (cache, report) = deploy(Cache::default(), opt(ConflictPolicy::Skip), config());
+        assert!(cache.mappings().is_empty());
+        assert_eq!(report.skipped.len(), 2);
+        assert!(!fs.is_symlink(Path::new("/home/.zshrc")));
+
+        let (cache, report) = deploy(cache, opt(ConflictPolicy::Ask), config());
+        assert_eq!(cache.mappings().len(), 1);
+        assert!(fs.is_symlink(Path::new("/home/.zshrc")));
+        assert!(fs.exists(Path::new("/home/.zshrc.backup")));
+        assert_eq!(report.skipped[0].path, PathBuf::from("/home/.vimrc"));
+        assert_eq!(report.skipped[0].reason, SkipReason::Conflict);
+    }
+
+    #[test]
+    fn changed_links() {
+        let fs = Arc::new(MemoryFs::new());
+        for file in ["zshrc", "vimrc", "vim/vimrc", "bashrc", "gitconfig"] {
+            fs.add_file(&Path::new("/dotfiles").join(file), b"")
+                .unwrap();
+        }
+        let config = |content: &str| {
+            let content = format!("allowed_root = /home\n{content}");
+            Config::parse(&content, PathBuf::from("/dotfiles/.george")).unwrap()
+        };
+        let opt = || DeployOptions::new(true).with_fs(fs.clone());
+
+        let before = "/home/.zshrc -> zshrc\n/home/.vimrc -> vimrc\n/home/.bashrc -> bashrc";
+        let (cache, _) = deploy(Cache::default(), opt(), config(before));
+        let after = config(
+            "/home/.zshrc -> zshrc\n/home/.vimrc -> vim/vimrc\n/home/.gitconfig -> gitconfig",
+        );
+        let diff = changes(&cache, &opt(), &after);
+
+        let names = |links: &[Mapping]| -> Vec<PathBuf> {
+            links.iter().map(|m| m.name().to_owned()).collect()
+        };
+        assert_eq!(names(&diff.added), vec![PathBuf::from("/home/.gitconfig")]);
+        assert_eq!(names(&diff.removed), vec![PathBuf::from("/home/.bashrc")]);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].1.target(), Path::new("/dotfiles/vim/vimrc"));
+        assert!(changes(&cache, &opt(), &config(before)).is_empty());
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn expanded_names_stay_below_name(
+            files in proptest::collection::vec(r"(config|dotfiles|\.?[a-c][a-c.]{0,2})(/(config|\.?[a-c][a-c.]{0,2})){0,3}", 1..8),
+            target in "/(dotfiles|config)(/config)?",
+            name in "/home(/config)?",
+        ) {
+            let fs = MemoryFs::new();
+            for file in files.iter() {
+                // Skip files whose parent was already added as a file
+                let _ = fs.add_file(&Path::new(&target).join(file), b"");
+            }
+            let mapping = Mapping::new(&name, &target);
+            let mut report = Report::default();
+            let links = expand_mappings(
+                &[mapping],
+                None,
+                &mut Snapshot::default(),
+                SymlinkPolicy::default(),
+                &mut report,
+                &fs,
+            );
 
-        let target = format!("{DOTFILE_DIR}/nvim");
-        fs::create_dir(format!("{DOTFILE_DIR}/nvim")).unwrap();
+            for link in links.iter() {
+                let rel = link.name().strip_prefix(&name);
+                proptest::prop_assert!(rel.is_ok(), "{:?} not below {name}", link.name());
+                proptest::prop_assert_eq!(rel.ok(), link.target().strip_prefix(&target).ok());
+            }
+        }
+    }
 
-        let init_target = &format!("{DOTFILE_DIR}/nvim/init.lua");
-        fs::write(init_target, "").unwrap();
+    #[test]
+    fn non_utf8_names() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
 
-        let name = format!("{HOME_DIR}/.config/nvim");
+        let file = OsStr::from_bytes(b"caf\xe9");
+        let fs = memory_fs(&[]);
+        fs.add_file(&Path::new("/dotfiles/dir").join(file), b"")
+            .unwrap();
 
-        let target2 = format!("{DOTFILE_DIR}/.vimrc");
-        fs::write(&target2, "").unwrap();
+        let config = memory_config("/home/dir -> dir");
+        let opt = DeployOptions::default().with_fs(fs.clone());
+        let (result, _) = deploy(Cache::default(), opt, config);
 
-        let name2 = format!("{HOME_DIR}/.vimrc");
+        assert_eq!(result.mappings().len(), 1);
+        assert!(fs.is_symlink(&Path::new("/home/dir").join(file)));
+    }
 
-        let config = Config::build(&format!(
-            "{name} -> {target}
-            {name2} -> {target2}"
-        ))
-        .unwrap();
-        let result = deploy(Cache::default(), DeployOptions::default(), config);
+    #[test]
+    fn replicate_symlink() {
+        let fs = memory_fs(&[]);
+        fs.create_dir_all(Path::new("/dotfiles/bin")).unwrap();
+        let link_target = Path::new("/dotfiles/bin/tool");
+        fs.symlink(Path::new("../tool-1.0"), link_target).unwrap();
+
+        let config = memory_config("/home/bin -> bin");
+        let opt = DeployOptions::new(true)
+            .with_symlinks(SymlinkPolicy::Replicate)
+            .with_fs(fs.clone());
+        let (result, _) = deploy(Cache::default(), opt, config);
+
+        let link = Path::new("/home/bin/tool");
+        let expected =
+            Mapping::new("/home/bin/tool", "/dotfiles/bin/tool").with_kind(LinkKind::Replica);
+        assert_eq!(result.mappings(), vec![expected]);
+        assert_eq!(fs.read_link(link).unwrap(), PathBuf::from("../tool-1.0"));
+
+        let (result, _) = clean::clean(result, CleanOptions::new(true).with_fs(fs.clone()));
+        assert!(result.mappings().is_empty());
+        assert!(!fs.is_symlink(link));
+    }
 
-        let init_link = format!("{name}/init.lua");
+    #[test]
+    fn directory_and_special_files() {
+        let (_dir, dotfiles, home) = setup();
+        let name = home.join(".local/state/foo");
+        fs::create_dir(dotfiles.join("run")).unwrap();
+        let status = std::process::Command::new("mkfifo")
+            .arg(dotfiles.join("run/pipe"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let content = format!(
+            "{} ->
+    type = dir
+    mode = 700
+{}/run -> run",
+            name.display(),
+            home.display()
+        );
+        let (cache, report) = deploy(
+            Cache::default(),
+            DeployOptions::default(),
+            config(&dotfiles, &content),
+        );
+        assert_eq!(
+            cache.mappings(),
+            vec![Mapping::directory(name.to_str().unwrap())]
+        );
+        let mode = fs::metadata(&name).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o700);
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].reason, SkipReason::Special);
+
+        let content = format!("{content}\n    special = true");
+        let (cache, report) = deploy(cache, DeployOptions::default(), config(&dotfiles, &content));
+        assert!(report.is_empty());
+        assert!(home.join("run/pipe").is_symlink());
+
+        // A directory that isn't empty anymore is left alone
+        fs::write(name.join("state"), "").unwrap();
+        let (cache, _) = clean::clean(cache, CleanOptions::new(true));
+        assert!(cache.mappings().is_empty());
+        assert!(name.is_dir());
+    }
 
-        let init_mapping = Mapping::new(&init_link, init_target);
-        assert!(result.contains(&init_mapping));
-        assert!(PathBuf::from(&init_link).exists());
-        assert!(PathBuf::from(&init_link).is_symlink());
-        assert!(PathBuf::from(&init_link)
-            .canonicalize()
-            .is_ok_and(|p| p == PathBuf::from(&init_target).canonicalize().unwrap()));
+    #[test]
+    fn apply_permissions() {
+        let (_dir, dotfiles, home) = setup();
+        fs::create_dir(dotfiles.join("ssh")).unwrap();
+        let target = dotfiles.join("ssh/config");
+        fs::write(&target, "").unwrap();
+        let ssh = home.join(".ssh");
 
-        let vimrc_mapping = Mapping::new(&name2, &target2);
-        assert!(result.contains(&vimrc_mapping));
-        assert!(PathBuf::from(&name2).exists());
-        assert!(PathBuf::from(&name2).is_symlink());
-        assert!(PathBuf::from(&name2)
-            .canonicalize()
-            .is_ok_and(|p| p == PathBuf::from(&target2).canonicalize().unwrap()));
+        let content = format!(
+            "{} -> ssh
+    mode = 600
+    dir_mode = 700",
+            ssh.display()
+        );
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        let home_mode = mode(&home);
+        let (cache, _) = deploy(
+            Cache::default(),
+            DeployOptions::default(),
+            config(&dotfiles, &content),
+        );
+        assert_eq!(mode(&target), 0o600);
+        assert_eq!(mode(&ssh), 0o700);
+
+        fs::set_permissions(&ssh, fs::Permissions::from_mode(0o755)).unwrap();
+        let drift = permissions::drift(&cache.mappings()[0], &RealFs);
+        assert_eq!(
+            drift,
+            vec![Drift::Mode {
+                path: ssh.canonicalize().unwrap(),
+                expected: 0o700,
+                actual: 0o755,
+            }]
+        );
+
+        // Unchanged links are only checked without the snapshot
+        let (cache, _) = deploy(cache, DeployOptions::default(), config(&dotfiles, &content));
+        assert_eq!(mode(&ssh), 0o755);
+        let opt = DeployOptions::default().with_snapshot(false);
+        deploy(cache, opt, config(&dotfiles, &content));
+        assert_eq!(mode(&ssh), 0o700);
+
+        // The existing directory a single file is linked into is left alone, created ones aren't
+        fs::write(dotfiles.join("zshrc"), "").unwrap();
+        let content = format!(
+            "{home}/.zshrc -> zshrc
+    dir_mode = 700
+{home}/.gnupg/gpg.conf -> zshrc
+    dir_mode = 700",
+            home = home.display()
+        );
+        deploy(
+            Cache::default(),
+            DeployOptions::default(),
+            config(&dotfiles, &content),
+        );
+        assert_eq!(mode(&home), home_mode);
+        assert_eq!(mode(&home.join(".gnupg")), 0o700);
+    }
+
+    #[test]
+    fn run_hooks() {
+        let (_dir, dotfiles, home) = setup();
+        fs::write(dotfiles.join(".zshrc"), "").unwrap();
+        fs::write(dotfiles.join(".vimrc"), "").unwrap();
+        let changes = home.join("changes");
+
+        let content = format!(
+            "post_deploy = touch {home}/post
+{home}/.zshrc -> .zshrc
+    on_change = echo zshrc >> {changes}
+{home}/.vimrc -> .vimrc
+    on_change = exit 3",
+            home = home.display(),
+            changes = changes.display()
+        );
+        let (cache, report) = deploy(
+            Cache::default(),
+            DeployOptions::default(),
+            config(&dotfiles, &content),
+        );
+        assert!(home.join("post").exists());
+        assert_eq!(fs::read_to_string(&changes).unwrap(), "zshrc\n");
+        assert_eq!(report.hooks.len(), 1);
+        assert_eq!(report.hooks[0].command, "exit 3");
+
+        // Mappings whose links didn't change don't run their hooks
+        let (_, report) = deploy(cache, DeployOptions::default(), config(&dotfiles, &content));
+        assert_eq!(fs::read_to_string(&changes).unwrap(), "zshrc\n");
+        assert!(report.hooks.is_empty());
+    }
+
+    #[test]
+    fn decrypt_secret() {
+        let (_dir, dotfiles, home) = setup();
+        let target = dotfiles.join("netrc.enc");
+        fs::write(&target, "machine example.com").unwrap();
+        let name = home.join(".netrc");
+        let backup = home.join(".netrc.backup");
+
+        let content = format!(
+            "decrypt = tr a-z A-Z <
+{} -> netrc.enc
+    type = secret",
+            name.display()
+        );
+        let (cache, _) = deploy(
+            Cache::default(),
+            DeployOptions::default(),
+            config(&dotfiles, &content),
+        );
+        assert!(!name.is_symlink());
+        assert_eq!(fs::read_to_string(&name).unwrap(), "MACHINE EXAMPLE.COM");
+        let mode = fs::metadata(&name).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o600);
+        let hash = secret::hash(b"MACHINE EXAMPLE.COM");
+        assert_eq!(cache.mappings()[0].hash(), Some(hash.as_str()));
+
+        // A changed secret replaces the decrypted copy
+        // The modification time may lag behind, as it's taken from a coarse clock
+        let update = |content: &str, secs| {
+            fs::write(&target, content).unwrap();
+            let modified = std::time::SystemTime::now() + std::time::Duration::from_secs(secs);
+            fs::File::options()
+                .write(true)
+                .open(&target)
+                .and_then(|f| f.set_modified(modified))
+                .unwrap();
+        };
+        update("machine example.org", 1);
+        let (cache, _) = deploy(cache, DeployOptions::default(), config(&dotfiles, &content));
+        assert_eq!(fs::read_to_string(&name).unwrap(), "MACHINE EXAMPLE.ORG");
+        assert!(!backup.exists());
+
+        let (cache, _) = clean::clean(cache, CleanOptions::new(true));
+        assert!(cache.mappings().is_empty());
+        assert!(!name.exists());
+
+        // A modified copy is neither replaced nor backed up
+        let (cache, _) = deploy(cache, DeployOptions::default(), config(&dotfiles, &content));
+        fs::write(&name, "edited").unwrap();
+        update("machine example.net", 2);
+        let (cache, _) = deploy(cache, DeployOptions::default(), config(&dotfiles, &content));
+        assert_eq!(fs::read_to_string(&name).unwrap(), "edited");
+        assert!(!backup.exists());
+        assert_eq!(cache.mappings().len(), 1);
     }
 }
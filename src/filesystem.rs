@@ -0,0 +1,647 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt::Debug,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::{self as unix_fs, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+/// The kind of a filesystem entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    /// A FIFO or socket
+    Special,
+    /// Anything else, e.g. a device
+    Other,
+}
+
+/// The metadata george needs about a filesystem entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: FileKind,
+    pub len: u64,
+    /// The permission bits
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub modified: SystemTime,
+}
+
+impl Metadata {
+    pub fn is_file(&self) -> bool {
+        self.kind == FileKind::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileKind::Dir
+    }
+}
+
+/// The filesystem links are deployed into and targets are read from. Paths are absolute.
+///
+/// Only the primitives have to be implemented, following symbolic links is built on
+/// `symlink_metadata` and `read_link` unless an implementation does it itself.
+pub trait Fs: Send + Sync {
+    /// The metadata of `path` itself, a symbolic link isn't followed
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata>;
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+    /// The names of the entries in the directory `path` and their kinds
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<(OsString, FileKind)>>;
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    /// Creates a file that doesn't exist yet with `content` and the permissions `mode`
+    fn create_file(&self, path: &Path, content: &[u8], mode: u32) -> io::Result<()>;
+    /// Replaces the content of an existing file and waits until it's written
+    fn overwrite(&self, path: &Path, content: &[u8]) -> io::Result<()>;
+    /// Creates a symbolic link at `path` containing `content`
+    fn symlink(&self, content: &Path, path: &Path) -> io::Result<()>;
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()>;
+    fn chown(&self, path: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()>;
+
+    /// The path on the real filesystem, e.g. for commands reading the file
+    fn host_path(&self, path: &Path) -> PathBuf {
+        path.to_owned()
+    }
+
+    /// Resolves all symbolic links and `..` in `path`, which has to exist
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        resolve(self, path)
+    }
+
+    /// The metadata of `path`, following symbolic links
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.symlink_metadata(&self.canonicalize(path)?)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        match self.metadata(path) {
+            Ok(metadata) if metadata.is_dir() => return Ok(()),
+            Ok(_) => return Err(io::ErrorKind::AlreadyExists.into()),
+            Err(_) => (),
+        }
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        self.create_dir(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|m| m.is_file())
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|m| m.is_dir())
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        self.symlink_metadata(path)
+            .is_ok_and(|m| m.kind == FileKind::Symlink)
+    }
+}
+
+impl Debug for dyn Fs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Fs")
+    }
+}
+
+/// The maximum number of symbolic links followed while resolving a path
+const MAX_LINKS: usize = 40;
+
+fn resolve<F: Fs + ?Sized>(fs: &F, path: &Path) -> io::Result<PathBuf> {
+    let mut resolved = PathBuf::from("/");
+    let mut pending: Vec<PathBuf> = components(path);
+    let mut links = 0;
+
+    while let Some(part) = pending.pop() {
+        if part == Path::new("..") {
+            resolved.pop();
+            continue;
+        }
+
+        let next = resolved.join(&part);
+        if fs.symlink_metadata(&next)?.kind != FileKind::Symlink {
+            resolved = next;
+            continue;
+        }
+
+        links += 1;
+        if links > MAX_LINKS {
+            return Err(io::Error::other("too many levels of symbolic links"));
+        }
+        let content = fs.read_link(&next)?;
+        if content.is_absolute() {
+            resolved = PathBuf::from("/");
+        }
+        pending.extend(components(&content));
+    }
+    Ok(resolved)
+}
+
+/// The normal components and `..` of `path` in reverse order
+fn components(path: &Path) -> Vec<PathBuf> {
+    path.components()
+        .rev()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(PathBuf::from(name)),
+            Component::ParentDir => Some(PathBuf::from("..")),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The real filesystem
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RealFs;
+
+fn metadata_from(metadata: fs::Metadata) -> Metadata {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_symlink() {
+        FileKind::Symlink
+    } else if file_type.is_dir() {
+        FileKind::Dir
+    } else if file_type.is_file() {
+        FileKind::File
+    } else if file_type.is_fifo() || file_type.is_socket() {
+        FileKind::Special
+    } else {
+        FileKind::Other
+    };
+
+    Metadata {
+        kind,
+        len: metadata.len(),
+        mode: metadata.permissions().mode() & 0o7777,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+    }
+}
+
+impl Fs for RealFs {
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::symlink_metadata(path).map(metadata_from)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<(OsString, FileKind)>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(path)?.filter_map(|e| e.ok()) {
+            if let Ok(metadata) = entry.metadata() {
+                entries.push((entry.file_name(), metadata_from(metadata).kind));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn create_file(&self, path: &Path, content: &[u8], mode: u32) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(path)?;
+        file.write_all(content)?;
+        file.sync_all()
+    }
+
+    fn overwrite(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.write_all(content)?;
+        file.sync_all()
+    }
+
+    fn symlink(&self, content: &Path, path: &Path) -> io::Result<()> {
+        unix_fs::symlink(content, path)
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+
+    fn chown(&self, path: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+        unix_fs::chown(path, uid, gid)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::metadata(path).map(metadata_from)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+}
+
+/// The real filesystem below `root`, which is treated as `/`. Symbolic links are resolved inside
+/// of it, like after a `chroot`.
+#[derive(Debug, Clone)]
+pub struct RootedFs {
+    root: PathBuf,
+}
+
+impl RootedFs {
+    pub fn new(root: PathBuf) -> RootedFs {
+        RootedFs { root }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The path on the real filesystem with all links but the last component resolved
+    fn entry(&self, path: &Path) -> io::Result<PathBuf> {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => {
+                Ok(self.host_path(&self.canonicalize(parent)?).join(name))
+            }
+            _ => Ok(self.root.clone()),
+        }
+    }
+
+    /// The path on the real filesystem with all links resolved
+    fn resolved(&self, path: &Path) -> io::Result<PathBuf> {
+        Ok(self.host_path(&self.canonicalize(path)?))
+    }
+}
+
+impl Fs for RootedFs {
+    /// No links are resolved
+    fn host_path(&self, path: &Path) -> PathBuf {
+        self.root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        RealFs.symlink_metadata(&self.entry(path)?)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        RealFs.read_link(&self.entry(path)?)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<(OsString, FileKind)>> {
+        // The kinds of symbolic links depend on what they point to inside of the root
+        let dir = self.canonicalize(path)?;
+        let mut entries = RealFs.read_dir(&self.host_path(&dir))?;
+        for (name, kind) in entries.iter_mut() {
+            if let Ok(metadata) = self.symlink_metadata(&dir.join(name)) {
+                *kind = metadata.kind;
+            }
+        }
+        Ok(entries)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        RealFs.read(&self.resolved(path)?)
+    }
+
+    fn create_file(&self, path: &Path, content: &[u8], mode: u32) -> io::Result<()> {
+        RealFs.create_file(&self.entry(path)?, content, mode)
+    }
+
+    fn overwrite(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        RealFs.overwrite(&self.resolved(path)?, content)
+    }
+
+    fn symlink(&self, content: &Path, path: &Path) -> io::Result<()> {
+        RealFs.symlink(content, &self.entry(path)?)
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        RealFs.create_dir(&self.entry(path)?)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        RealFs.remove_file(&self.entry(path)?)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        RealFs.remove_dir(&self.entry(path)?)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        RealFs.rename(&self.entry(from)?, &self.entry(to)?)
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        RealFs.set_mode(&self.resolved(path)?, mode)
+    }
+
+    fn chown(&self, path: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+        RealFs.chown(&self.resolved(path)?, uid, gid)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Content {
+    File(Vec<u8>),
+    Dir,
+    Symlink(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    content: Content,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    modified: SystemTime,
+}
+
+impl Node {
+    fn new(content: Content, mode: u32) -> Node {
+        Node {
+            content,
+            mode,
+            uid: 0,
+            gid: 0,
+            modified: SystemTime::now(),
+        }
+    }
+}
+
+/// A filesystem kept in memory, it only contains `/` when created
+#[derive(Debug)]
+pub struct MemoryFs {
+    nodes: Mutex<BTreeMap<PathBuf, Node>>,
+}
+
+impl Default for MemoryFs {
+    fn default() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(PathBuf::from("/"), Node::new(Content::Dir, 0o755));
+        MemoryFs {
+            nodes: Mutex::new(nodes),
+        }
+    }
+}
+
+fn not_found() -> io::Error {
+    io::ErrorKind::NotFound.into()
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    /// Creates the file `path` and its parent directories
+    pub fn add_file(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        self.create_file(path, content, 0o644)
+    }
+
+    /// The path with all links but the last component resolved
+    fn entry(&self, path: &Path) -> io::Result<PathBuf> {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => Ok(self.canonicalize(parent)?.join(name)),
+            _ => Ok(PathBuf::from("/")),
+        }
+    }
+
+    /// Adds a node to an existing directory, updating its modification time
+    fn insert(&self, path: &Path, node: Node) -> io::Result<()> {
+        let path = self.entry(path)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.contains_key(&path) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        let parent = path.parent().and_then(|p| nodes.get_mut(p));
+        match parent {
+            Some(Node {
+                content: Content::Dir,
+                modified,
+                ..
+            }) => *modified = SystemTime::now(),
+            _ => return Err(not_found()),
+        }
+        nodes.insert(path, node);
+        Ok(())
+    }
+
+    /// Removes a node, which has to be a directory if `dir` is set
+    fn remove(&self, path: &Path, dir: bool) -> io::Result<Node> {
+        let path = self.entry(path)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        match nodes.get(&path).map(|n| &n.content) {
+            None => return Err(not_found()),
+            Some(Content::Dir) if !dir => return Err(io::ErrorKind::IsADirectory.into()),
+            Some(Content::Dir) => {
+                if nodes.keys().any(|p| p.parent() == Some(&path)) {
+                    return Err(io::ErrorKind::DirectoryNotEmpty.into());
+                }
+            }
+            Some(_) if dir => return Err(io::ErrorKind::NotADirectory.into()),
+            Some(_) => (),
+        }
+        if let Some(parent) = path.parent().and_then(|p| nodes.get_mut(p)) {
+            parent.modified = SystemTime::now();
+        }
+        nodes.remove(&path).ok_or_else(not_found)
+    }
+
+    /// Changes the node at the resolved `path`
+    fn update(&self, path: &Path, f: impl FnOnce(&mut Node) -> io::Result<()>) -> io::Result<()> {
+        let path = self.canonicalize(path)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        f(nodes.get_mut(&path).ok_or_else(not_found)?)
+    }
+}
+
+impl Fs for MemoryFs {
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let path = self.entry(path)?;
+        let nodes = self.nodes.lock().unwrap();
+        let node = nodes.get(&path).ok_or_else(not_found)?;
+        let (kind, len) = match &node.content {
+            Content::File(content) => (FileKind::File, content.len() as u64),
+            Content::Dir => (FileKind::Dir, 0),
+            Content::Symlink(content) => (FileKind::Symlink, content.as_os_str().len() as u64),
+        };
+        Ok(Metadata {
+            kind,
+            len,
+            mode: node.mode,
+            uid: node.uid,
+            gid: node.gid,
+            modified: node.modified,
+        })
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        let path = self.entry(path)?;
+        match self.nodes.lock().unwrap().get(&path).map(|n| &n.content) {
+            Some(Content::Symlink(content)) => Ok(content.clone()),
+            Some(_) => Err(io::ErrorKind::InvalidInput.into()),
+            None => Err(not_found()),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<(OsString, FileKind)>> {
+        let dir = self.canonicalize(path)?;
+        if !self.symlink_metadata(&dir)?.is_dir() {
+            return Err(io::ErrorKind::NotADirectory.into());
+        }
+
+        let nodes = self.nodes.lock().unwrap();
+        let children = nodes
+            .iter()
+            .filter(|(p, _)| p.parent() == Some(&dir))
+            .map(|(p, node)| {
+                let kind = match node.content {
+                    Content::File(_) => FileKind::File,
+                    Content::Dir => FileKind::Dir,
+                    Content::Symlink(_) => FileKind::Symlink,
+                };
+                (p.file_name().unwrap().to_owned(), kind)
+            });
+        Ok(children.collect())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let path = self.canonicalize(path)?;
+        match self.nodes.lock().unwrap().get(&path).map(|n| &n.content) {
+            Some(Content::File(content)) => Ok(content.clone()),
+            Some(_) => Err(io::ErrorKind::IsADirectory.into()),
+            None => Err(not_found()),
+        }
+    }
+
+    fn create_file(&self, path: &Path, content: &[u8], mode: u32) -> io::Result<()> {
+        self.insert(path, Node::new(Content::File(content.to_owned()), mode))
+    }
+
+    fn overwrite(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        self.update(path, |node| match &mut node.content {
+            Content::File(old) => {
+                *old = content.to_owned();
+                node.modified = SystemTime::now();
+                Ok(())
+            }
+            _ => Err(io::ErrorKind::IsADirectory.into()),
+        })
+    }
+
+    fn symlink(&self, content: &Path, path: &Path) -> io::Result<()> {
+        self.insert(path, Node::new(Content::Symlink(content.to_owned()), 0o777))
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.insert(path, Node::new(Content::Dir, 0o755))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.remove(path, false).map(|_| ())
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.remove(path, true).map(|_| ())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let node = self.remove(from, false)?;
+        self.insert(to, node)
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.update(path, |node| {
+            node.mode = mode;
+            Ok(())
+        })
+    }
+
+    fn chown(&self, path: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+        self.update(path, |node| {
+            node.uid = uid.unwrap_or(node.uid);
+            node.gid = gid.unwrap_or(node.gid);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_links() {
+        let fs = MemoryFs::new();
+        fs.add_file(Path::new("/dotfiles/nvim/init.lua"), b"vim")
+            .unwrap();
+        fs.create_dir_all(Path::new("/home/.config")).unwrap();
+        fs.symlink(Path::new("/dotfiles/nvim"), Path::new("/home/.config/nvim"))
+            .unwrap();
+        fs.symlink(Path::new("../missing"), Path::new("/home/dangling"))
+            .unwrap();
+
+        let init = Path::new("/home/.config/nvim/init.lua");
+        assert_eq!(fs.read(init).unwrap(), b"vim");
+        assert_eq!(
+            fs.canonicalize(init).unwrap(),
+            PathBuf::from("/dotfiles/nvim/init.lua")
+        );
+        assert!(fs.is_symlink(Path::new("/home/dangling")));
+        assert!(!fs.exists(Path::new("/home/dangling")));
+        assert_eq!(
+            fs.read_dir(Path::new("/home/.config")).unwrap(),
+            vec![(OsString::from("nvim"), FileKind::Symlink)]
+        );
+        assert!(fs.remove_dir(Path::new("/dotfiles")).is_err());
+    }
+
+    #[test]
+    fn rooted_links() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("etc")).unwrap();
+        fs::write(dir.path().join("etc/hosts"), "rooted").unwrap();
+
+        let fs = RootedFs::new(dir.path().canonicalize().unwrap());
+        fs.symlink(Path::new("/etc/hosts"), Path::new("/hosts"))
+            .unwrap();
+        // Absolute links resolve inside of the root, `..` can't leave it
+        assert_eq!(fs.read(Path::new("/hosts")).unwrap(), b"rooted");
+        assert_eq!(fs.read(Path::new("/../etc/hosts")).unwrap(), b"rooted");
+        assert!(dir.path().join("hosts").is_symlink());
+    }
+}
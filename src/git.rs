@@ -0,0 +1,293 @@
+use std::{
+    ffi::OsStr,
+    fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use crate::expand_path;
+
+/// Runs `git` with `args` inside `dir` and returns its output without a trailing newline
+fn git<S: AsRef<OsStr>>(dir: &Path, args: impl IntoIterator<Item = S>) -> io::Result<Vec<u8>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!("git failed: {}", stderr.trim())));
+    }
+
+    let mut stdout = output.stdout;
+    if stdout.last() == Some(&b'\n') {
+        stdout.pop();
+    }
+    Ok(stdout)
+}
+
+fn to_path(output: &[u8]) -> PathBuf {
+    PathBuf::from(OsStr::from_bytes(output))
+}
+
+/// The top-level directory of the repository containing `dir`, `None` if it isn't inside one
+pub fn toplevel(dir: &Path) -> Option<PathBuf> {
+    git(dir, ["rev-parse", "--show-toplevel"])
+        .ok()
+        .map(|output| to_path(&output))
+}
+
+/// The commit checked out in the repository containing `dir`
+pub fn head(dir: &Path) -> Option<String> {
+    resolve(dir, "HEAD").ok()
+}
+
+/// The full hash of the commit `rev` refers to, e.g. a branch, tag or abbreviated hash
+pub fn resolve(dir: &Path, rev: &str) -> io::Result<String> {
+    let rev = format!("{rev}^{{commit}}");
+    let output = git(dir, ["rev-parse", "--verify", "--end-of-options", &rev])?;
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+/// The files with uncommitted changes in the repository containing `dir`, untracked files
+/// included
+pub fn uncommitted(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let top = toplevel(dir).ok_or_else(|| io::Error::other("not inside a git repository"))?;
+    let output = git(
+        &top,
+        ["status", "--porcelain", "-z", "--untracked-files=all"],
+    )?;
+
+    let mut paths = Vec::new();
+    let mut entries = output.split(|b| *b == 0);
+    while let Some(entry) = entries.next() {
+        // Each entry is the two letter status, a space and the path
+        if entry.len() < 4 {
+            continue;
+        }
+        paths.push(top.join(to_path(&entry[3..])));
+        // Renames and copies are followed by the original path
+        if matches!(entry[0], b'R' | b'C') {
+            entries.next();
+        }
+    }
+    Ok(paths)
+}
+
+/// Clones the repository at `url`, which can be any URL or path git understands, into `dir`
+pub fn clone(url: &str, dir: &Path) -> io::Result<()> {
+    // Git runs in the parent, so local paths have to be made absolute first
+    let dir = expand_path(dir);
+    let url = match Path::new(url).exists() {
+        true => expand_path(url).into_os_string(),
+        false => url.into(),
+    };
+    let parent = dir.parent().unwrap_or(Path::new("/"));
+    fs::create_dir_all(parent)?;
+    let args = [OsStr::new("clone"), OsStr::new("--quiet"), OsStr::new("--")];
+    git(
+        parent,
+        args.iter().chain([&url.as_os_str(), &dir.as_os_str()]),
+    )?;
+    Ok(())
+}
+
+/// The directory git clones `url` into by default, e.g. `dotfiles` for
+/// `git@github.com:me/dotfiles.git`
+pub fn clone_dir(url: &str) -> &str {
+    let name = url.trim_end_matches('/').rsplit(['/', ':']).next().unwrap();
+    name.strip_suffix(".git").unwrap_or(name)
+}
+
+/// Fast-forwards the repository containing `dir` to its upstream branch, fails if the branches
+/// diverged or uncommitted changes would be overwritten
+pub fn pull(dir: &Path) -> io::Result<()> {
+    git(dir, ["pull", "--ff-only", "--quiet"])?;
+    Ok(())
+}
+
+/// The directory the worktrees checked out by george are kept in, inside the git directory of
+/// the repository containing `dir`
+fn worktrees_dir(dir: &Path) -> io::Result<PathBuf> {
+    let output = git(
+        dir,
+        ["rev-parse", "--path-format=absolute", "--git-common-dir"],
+    )?;
+    Ok(to_path(&output).join("george/worktrees"))
+}
+
+/// Checks out `rev` of the repository containing `dir` into a detached worktree and returns its
+/// path. There is one worktree per commit, which is reused by later deploys of that commit.
+pub fn checkout(dir: &Path, rev: &str) -> io::Result<PathBuf> {
+    let commit = resolve(dir, rev)?;
+    let path = worktrees_dir(dir)?.join(&commit);
+    if head(&path).is_some_and(|head| head == commit) {
+        return Ok(path);
+    }
+
+    fs::create_dir_all(path.parent().unwrap())?;
+    let args = [
+        OsStr::new("worktree"),
+        OsStr::new("add"),
+        OsStr::new("--detach"),
+    ];
+    git(
+        dir,
+        args.iter().chain([&path.as_os_str(), &OsStr::new(&commit)]),
+    )?;
+    Ok(path)
+}
+
+/// Removes the worktrees checked out by george that no path in `used` lies in
+pub fn remove_unused_worktrees(dir: &Path, used: &[PathBuf]) -> io::Result<()> {
+    let worktrees = worktrees_dir(dir)?;
+    let output = git(dir, ["worktree", "list", "--porcelain", "-z"])?;
+
+    for entry in output.split(|b| *b == 0) {
+        let Some(path) = entry.strip_prefix(b"worktree ").map(to_path) else {
+            continue;
+        };
+        if path.starts_with(&worktrees) && !used.iter().any(|p| p.starts_with(&path)) {
+            let args = [
+                OsStr::new("worktree"),
+                OsStr::new("remove"),
+                OsStr::new("--force"),
+            ];
+            git(dir, args.iter().chain([&path.as_os_str()]))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::Cache,
+        config::Config,
+        deploy::{deploy, ConflictPolicy, DeployOptions},
+    };
+
+    fn run(dir: &Path, args: &[&str]) {
+        let identity = [
+            "-c",
+            "user.name=george",
+            "-c",
+            "user.email=george@localhost",
+        ];
+        git(dir, identity.iter().chain(args)).unwrap();
+    }
+
+    #[test]
+    fn worktree_of_bare_clone() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        run(dir, &["init", "--bare", "-q", "remote.git"]);
+        run(dir, &["clone", "-q", "remote.git", "dotfiles"]);
+        let repo = dir.join("dotfiles");
+        for content in ["one", "two"] {
+            fs::write(repo.join("zshrc"), content).unwrap();
+            run(&repo, &["add", "zshrc"]);
+            run(&repo, &["commit", "-q", "-m", content]);
+        }
+        run(&repo, &["push", "-q", "origin", "HEAD"]);
+
+        let copy = dir.join("copy/dotfiles");
+        clone(dir.join("remote.git").to_str().unwrap(), &copy).unwrap();
+        assert_eq!(fs::read_to_string(copy.join("zshrc")).unwrap(), "two");
+
+        let first = resolve(&repo, "HEAD~1").unwrap();
+        let worktree = checkout(&repo, "HEAD~1").unwrap();
+        assert_eq!(head(&worktree), Some(first));
+        assert_eq!(fs::read_to_string(worktree.join("zshrc")).unwrap(), "one");
+        assert_eq!(checkout(&repo, "HEAD~1").unwrap(), worktree);
+
+        fs::write(repo.join("zshrc"), "three").unwrap();
+        fs::write(repo.join("vimrc"), "").unwrap();
+        let mut changed = uncommitted(&repo).unwrap();
+        changed.sort();
+        let repo = repo.canonicalize().unwrap();
+        assert_eq!(changed, vec![repo.join("vimrc"), repo.join("zshrc")]);
+
+        remove_unused_worktrees(&repo, &[worktree.join("zshrc")]).unwrap();
+        assert!(worktree.exists());
+        remove_unused_worktrees(&repo, &[]).unwrap();
+        assert!(!worktree.exists());
+    }
+
+    #[test]
+    fn clone_and_deploy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        run(dir, &["init", "--bare", "-q", "remote.git"]);
+        run(dir, &["clone", "-q", "remote.git", "dotfiles"]);
+        let repo = dir.join("dotfiles");
+        let home = dir.join("home");
+        let content = format!(
+            "allowed_root = {home}\n{home}/.zshrc -> zshrc\n",
+            home = home.display()
+        );
+        fs::write(repo.join(".george"), content).unwrap();
+        fs::write(repo.join("zshrc"), "").unwrap();
+        run(&repo, &["add", "."]);
+        run(&repo, &["commit", "-q", "-m", "dotfiles"]);
+        run(&repo, &["push", "-q", "origin", "HEAD"]);
+
+        // Relative paths are relative to the current directory, not to the parent of the clone
+        let cwd = std::env::current_dir().unwrap();
+        let up = "../".repeat(cwd.components().count() - 1);
+        let remote = dir.join("remote.git");
+        let url = format!("{up}{}", remote.strip_prefix("/").unwrap().display());
+        let copy = dir.join("copy/dotfiles");
+        clone(&url, &copy).unwrap();
+        let config = Config::build(copy.join(".george")).unwrap();
+        let opt = DeployOptions::default().with_conflicts(ConflictPolicy::Skip);
+        let (cache, report) = deploy(Cache::default(), opt, config);
+
+        assert!(report.is_empty());
+        assert_eq!(cache.mappings().len(), 1);
+        let link = home.join(".zshrc");
+        assert_eq!(fs::read_link(link).unwrap(), copy.join("zshrc"));
+        assert_eq!(cache.commit(), head(&copy).as_deref());
+    }
+
+    #[test]
+    fn clone_dir_of_url() {
+        assert_eq!(clone_dir("git@github.com:me/dotfiles.git"), "dotfiles");
+        assert_eq!(clone_dir("https://example.com/me/dots/"), "dots");
+        assert_eq!(clone_dir("../dotfiles"), "dotfiles");
+    }
+
+    #[test]
+    fn pull_fast_forward_only() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        run(dir, &["init", "--bare", "-q", "remote.git"]);
+        let url = format!("file://{}", dir.join("remote.git").display());
+        let (local, other) = (dir.join("local"), dir.join("other"));
+        run(dir, &["clone", "-q", &url, "other"]);
+        let commit = |repo: &Path, file: &str| {
+            fs::write(repo.join(file), "").unwrap();
+            run(repo, &["add", file]);
+            run(repo, &["commit", "-q", "-m", file]);
+            run(repo, &["push", "-q", "origin", "HEAD"]);
+        };
+        commit(&other, "zshrc");
+        run(dir, &["clone", "-q", &url, "local"]);
+
+        commit(&other, "vimrc");
+        pull(&local).unwrap();
+        assert_eq!(head(&local), head(&other));
+
+        // Diverged branches aren't merged
+        fs::write(local.join("bashrc"), "").unwrap();
+        run(&local, &["add", "bashrc"]);
+        run(&local, &["commit", "-q", "-m", "bashrc"]);
+        commit(&other, "gitconfig");
+        let before = head(&local);
+        assert!(pull(&local).is_err());
+        assert_eq!(head(&local), before);
+    }
+}
@@ -0,0 +1,53 @@
+use std::{path::Path, process::Command};
+
+use log::info;
+
+use crate::report::HookFailure;
+
+/// The commands set in the config that run before and after deploying and cleaning
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Hooks {
+    pub pre_deploy: Option<String>,
+    pub post_deploy: Option<String>,
+    pub pre_clean: Option<String>,
+    pub post_clean: Option<String>,
+}
+
+impl Hooks {
+    /// Sets the hook called `name`, returns false if there is no such hook
+    pub(crate) fn set(&mut self, name: &str, command: &str) -> bool {
+        let hook = match name {
+            "pre_deploy" => &mut self.pre_deploy,
+            "post_deploy" => &mut self.post_deploy,
+            "pre_clean" => &mut self.pre_clean,
+            "post_clean" => &mut self.post_clean,
+            _ => return false,
+        };
+        *hook = Some(command.to_owned());
+        true
+    }
+}
+
+/// Runs the hook `name` if it's set, the command is run by `sh` inside `dir`
+pub(crate) fn run(name: &str, command: Option<&str>, dir: &Path) -> Result<(), HookFailure> {
+    let Some(command) = command else {
+        return Ok(());
+    };
+
+    info!("running {} hook: {}", name, command);
+    let failure = |error: String| HookFailure {
+        hook: name.to_owned(),
+        command: command.to_owned(),
+        error,
+    };
+    match Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(dir)
+        .status()
+    {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(failure(status.to_string())),
+        Err(e) => Err(failure(e.to_string())),
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    fs, io,
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+use walkdir::WalkDir;
+
+use crate::{config, pretty_path};
+
+/// The dotfiles below `$HOME` that are adopted if they exist
+const COMMON_DOTFILES: &[&str] = &[
+    ".bashrc",
+    ".bash_profile",
+    ".profile",
+    ".zshrc",
+    ".zprofile",
+    ".vimrc",
+    ".gitconfig",
+    ".tmux.conf",
+    ".inputrc",
+    ".config/nvim",
+    ".config/git",
+    ".config/alacritty",
+    ".config/kitty",
+];
+
+const STARTER_CONFIG: &str = "\
+# Each line maps a link to a target, targets are relative to this directory:
+#
+#     ~/.zshrc -> zshrc
+#     ~/.config/nvim -> config/nvim
+#
+# Directories are linked file by file. Indented lines set options of the mapping above:
+#
+#     ~/.ssh -> ssh
+#         dir_mode = 700
+#
+# Unindented lines set hooks and other settings:
+#
+#     post_deploy = echo deployed
+";
+
+#[derive(Debug, Default)]
+pub struct InitOptions {
+    /// The dotfiles directory to create
+    dir: PathBuf,
+    /// Copy the common dotfiles found in this directory into the dotfiles directory
+    adopt: Option<PathBuf>,
+    /// Register the config so it's found from any directory
+    register: bool,
+}
+
+impl InitOptions {
+    pub fn new(dir: PathBuf) -> Self {
+        InitOptions {
+            dir,
+            adopt: None,
+            register: true,
+        }
+    }
+
+    pub fn with_adopt(mut self, home: Option<PathBuf>) -> Self {
+        self.adopt = home;
+        self
+    }
+
+    pub fn with_register(mut self, register: bool) -> Self {
+        self.register = register;
+        self
+    }
+}
+
+/// Creates the dotfiles directory with a starter config and returns the path of the config.
+/// Adopted dotfiles are copied, not moved, the originals are backed up on the first deploy.
+pub fn init(opt: InitOptions) -> io::Result<PathBuf> {
+    let path = opt.dir.join(".george");
+    if path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", pretty_path(&path)),
+        ));
+    }
+    fs::create_dir_all(&opt.dir)?;
+
+    let mut content = STARTER_CONFIG.to_owned();
+    if let Some(home) = &opt.adopt {
+        let adopted = adopt(home, &opt.dir)?;
+        if !adopted.is_empty() {
+            content.push('\n');
+        }
+        for name in adopted {
+            content.push_str(&format!("~/{} -> {}\n", name, name.trim_start_matches('.')));
+        }
+    }
+    fs::write(&path, content)?;
+    info!("created {}", pretty_path(&path));
+
+    // The config can't be moved or renamed when it's registered, so the path has to be absolute
+    let path = path.canonicalize()?;
+    if opt.register {
+        register(&path)?;
+    }
+    Ok(path)
+}
+
+/// Copies the common dotfiles that exist in `home` into `dir` and returns their names
+fn adopt(home: &Path, dir: &Path) -> io::Result<Vec<&'static str>> {
+    let mut adopted = Vec::new();
+    for name in COMMON_DOTFILES {
+        let from = home.join(name);
+        // Symbolic links are most likely managed already
+        if !from.exists() || from.is_symlink() {
+            continue;
+        }
+
+        copy_all(&from, &dir.join(name.trim_start_matches('.')))?;
+        info!("adopted {}", pretty_path(&from));
+        adopted.push(*name);
+    }
+    Ok(adopted)
+}
+
+/// Copies the file or directory `from` to `to`
+fn copy_all(from: &Path, to: &Path) -> io::Result<()> {
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let dest = match entry.path().strip_prefix(from).unwrap() {
+            rest if rest.as_os_str().is_empty() => to.to_owned(),
+            rest => to.join(rest),
+        };
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(dest)?;
+        } else if entry.file_type().is_file() {
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(entry.path(), dest)?;
+        }
+    }
+    Ok(())
+}
+
+/// Links the registered config location to the config at `path`
+fn register(path: &Path) -> io::Result<()> {
+    let Some(registered) = config::registered_path() else {
+        warn!("failed to expand both $HOME and $XDG_CONFIG_HOME, can't register the config");
+        return Ok(());
+    };
+    if registered.exists() && !registered.is_symlink() {
+        warn!(
+            "{} exists and isn't a link created by george, not registering the config",
+            pretty_path(&registered)
+        );
+        return Ok(());
+    }
+
+    if let Some(parent) = registered.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if registered.is_symlink() {
+        fs::remove_file(&registered)?;
+    }
+    symlink(path, &registered)?;
+    info!("registered {} as the default config", pretty_path(path));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, expand_path};
+
+    #[test]
+    fn adopt_dotfiles() {
+        let tmp = tempfile::tempdir().unwrap();
+        let home = tmp.path().join("home");
+        fs::create_dir_all(home.join(".config/nvim/lua")).unwrap();
+        fs::write(home.join(".config/nvim/lua/init.lua"), "").unwrap();
+        fs::write(home.join(".zshrc"), "zsh").unwrap();
+        let dir = tmp.path().join("dotfiles");
+
+        let opt = InitOptions::new(dir.clone())
+            .with_adopt(Some(home))
+            .with_register(false);
+        let path = init(opt).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("zshrc")).unwrap(), "zsh");
+        assert!(dir.join("config/nvim/lua/init.lua").exists());
+
+        let config = Config::build(path.clone()).unwrap();
+        let names: Vec<&Path> = config.mappings().iter().map(|m| m.name()).collect();
+        assert_eq!(
+            names,
+            vec![expand_path("~/.zshrc"), expand_path("~/.config/nvim")]
+        );
+        assert_eq!(config.mappings()[0].target(), &path.with_file_name("zshrc"));
+
+        // An existing config isn't overwritten
+        assert!(init(InitOptions::new(dir).with_register(false)).is_err());
+    }
+}
@@ -1,35 +1,165 @@
 use std::{
     fmt::Display,
+    hash::{Hash, Hasher},
+    io,
     path::{Path, PathBuf},
 };
 
-use once_cell::sync::Lazy;
+use chrono::{DateTime, Local};
+use filesystem::Fs;
 use path_absolutize::*;
 use serde::{Deserialize, Serialize};
 
 pub mod cache;
+pub mod check;
 pub mod clean;
 pub mod config;
 pub mod deploy;
+pub mod filesystem;
+pub mod git;
+pub mod hooks;
+pub mod init;
+pub mod list;
+pub mod parallel;
+pub mod permissions;
+pub mod report;
+pub mod secret;
+pub mod snapshot;
+pub mod staging;
+pub mod status;
+pub mod watch;
 
-pub static HOME_DIR: Lazy<Option<String>> = Lazy::new(|| {
-    if let Ok(cow) = shellexpand::env("$HOME") {
-        Some(cow.to_string())
-    } else {
-        None
+/// The home directory, `$HOME` or the one of the user if it isn't set. It's read on every call,
+/// as `~` in the config is expanded with it as well.
+pub fn home_dir() -> Option<PathBuf> {
+    dirs::home_dir()
+}
+
+/// How a link is realized on disk
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkKind {
+    /// A symbolic link pointing to the target
+    #[default]
+    Symlink,
+    /// A copy of the symbolic link that is the target
+    Replica,
+    /// An empty directory, there is no target
+    Directory,
+    /// A decrypted copy of the encrypted target
+    Secret,
+}
+
+impl Display for LinkKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkKind::Symlink => write!(f, "symlink"),
+            LinkKind::Replica => write!(f, "replica"),
+            LinkKind::Directory => write!(f, "directory"),
+            LinkKind::Secret => write!(f, "secret"),
+        }
     }
-});
+}
 
+/// The config line a mapping was read from
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub struct Origin {
+    /// The config file containing the line
+    pub config: PathBuf,
+    /// The line number (starting at 1)
+    pub line: usize,
+    /// The name of the top-level mapping as written in the config
+    pub name: PathBuf,
+    /// The target of the top-level mapping as written in the config
+    pub target: PathBuf,
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {} of {}", self.line, pretty_path(&self.config))
+    }
+}
+
+/// The options set for a mapping in the config
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct MappingOptions {
+    /// The permissions of the linked file or the created directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// The permissions of the directories inside the mapping and of those created for its links
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir_mode: Option<u32>,
+    /// The user owning the linked file and the directories, a name or id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// The group owning the linked file and the directories, a name or id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// A command run after links of the mapping were created or removed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_change: Option<String>,
+    /// The command decrypting a secret, it gets the path of the encrypted file as argument
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decrypt: Option<String>,
+    /// Whether FIFOs and sockets inside the target directory are linked
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub special: bool,
+    /// Whether the link is created by the elevated system deploy, e.g. below `/etc`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub system: bool,
+}
+
+impl MappingOptions {
+    fn is_default(&self) -> bool {
+        *self == MappingOptions::default()
+    }
+}
+
+/// Two mappings are equal if they link the same name to the same target in the same way, the
+/// metadata is ignored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Mapping {
     /// The name of the link (i.e. the destination)
     name: PathBuf,
     /// The target that will be pointed to
     target: PathBuf,
+    #[serde(default)]
+    kind: LinkKind,
+    /// The config line this mapping was created from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    origin: Option<Origin>,
+    /// When the link was created
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created: Option<DateTime<Local>>,
+    /// The SHA-256 hash of a decrypted secret
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    #[serde(default, skip_serializing_if = "MappingOptions::is_default")]
+    options: MappingOptions,
+}
+
+impl PartialEq for Mapping {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.target == other.target && self.kind == other.kind
+    }
+}
+
+impl Eq for Mapping {}
+
+impl Hash for Mapping {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.target.hash(state);
+        self.kind.hash(state);
+    }
 }
 
 impl Display for Mapping {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.kind == LinkKind::Directory {
+            return write!(f, "[{} (directory)]", pretty_path(&self.name));
+        }
+
         write!(
             f,
             "[{} -> {}]",
@@ -39,14 +169,106 @@ impl Display for Mapping {
     }
 }
 
+/// Restricts an operation to the links below some paths and/or from one top-level mapping,
+/// an empty selection selects everything
+#[derive(Debug, Default, Clone)]
+pub struct Selection {
+    paths: Vec<PathBuf>,
+    mapping: Option<PathBuf>,
+}
+
+impl Selection {
+    pub fn new(paths: Vec<PathBuf>, mapping: Option<PathBuf>) -> Selection {
+        Selection { paths, mapping }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty() && self.mapping.is_none()
+    }
+
+    pub fn matches(&self, mapping: &Mapping) -> bool {
+        let in_paths =
+            self.paths.is_empty() || self.paths.iter().any(|p| mapping.name.starts_with(p));
+        let from_mapping = self
+            .mapping
+            .as_ref()
+            .is_none_or(|name| mapping.is_from(name));
+        in_paths && from_mapping
+    }
+
+    /// Narrows a top-level mapping down to the existing parts that can contain selected links,
+    /// so that only those have to be expanded
+    pub fn narrow(&self, mapping: &Mapping, fs: &dyn Fs) -> Vec<Mapping> {
+        if self
+            .mapping
+            .as_ref()
+            .is_some_and(|name| !mapping.is_from(name))
+        {
+            return Vec::new();
+        }
+        if self.paths.is_empty() || self.paths.iter().any(|p| mapping.name.starts_with(p)) {
+            return vec![mapping.to_owned()];
+        }
+
+        let mut narrowed = Vec::new();
+        if mapping.kind == LinkKind::Directory {
+            return narrowed;
+        }
+        for path in self.paths.iter() {
+            if let Ok(rest) = path.strip_prefix(&mapping.name) {
+                let mut part = mapping.to_owned();
+                part.name = mapping.name.join(rest);
+                part.target = mapping.target.join(rest);
+                if fs.exists(&part.target) {
+                    narrowed.push(part);
+                }
+            }
+        }
+        narrowed
+    }
+}
+
+/// Displays a mapping followed by the config line it came from, used for warnings and errors
+pub struct WithOrigin<'a>(&'a Mapping);
+
+impl Display for WithOrigin<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0.origin {
+            Some(origin) => write!(f, "{} (from {})", self.0, origin),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
 impl Mapping {
     pub fn new(name: &str, target: &str) -> Mapping {
-        let name = shellexpand::tilde(name).to_string();
-        let target = shellexpand::tilde(target).to_string();
+        Mapping {
+            name: expand_path(name),
+            target: expand_path(target),
+            kind: LinkKind::default(),
+            origin: None,
+            created: None,
+            hash: None,
+            options: MappingOptions::default(),
+        }
+    }
+
+    /// A mapping creating an empty directory at `name`
+    pub fn directory(name: &str) -> Mapping {
+        let mut mapping = Mapping::new(name, "");
+        mapping.target = PathBuf::new();
+        mapping.kind = LinkKind::Directory;
+        mapping
+    }
+
+    pub fn with_kind(mut self, kind: LinkKind) -> Mapping {
+        self.kind = kind;
+        self
+    }
 
-        let name = PathBuf::from(name).absolutize().unwrap().into();
-        let target = PathBuf::from(target).absolutize().unwrap().into();
-        Mapping { name, target }
+    pub fn with_origin(mut self, origin: Option<Origin>) -> Mapping {
+        self.origin = origin;
+        self
     }
 
     pub fn name(&self) -> &Path {
@@ -60,13 +282,95 @@ impl Mapping {
     pub fn target(&self) -> &Path {
         &self.target
     }
+
+    pub fn kind(&self) -> LinkKind {
+        self.kind
+    }
+
+    pub fn options(&self) -> &MappingOptions {
+        &self.options
+    }
+
+    /// What the created symbolic link contains
+    pub fn link_content(&self, fs: &dyn Fs) -> io::Result<PathBuf> {
+        match self.kind {
+            LinkKind::Symlink => Ok(self.target.to_owned()),
+            LinkKind::Replica => fs.read_link(&self.target),
+            LinkKind::Directory | LinkKind::Secret => Err(io::ErrorKind::InvalidInput.into()),
+        }
+    }
+
+    pub fn origin(&self) -> Option<&Origin> {
+        self.origin.as_ref()
+    }
+
+    pub fn with_source(&self) -> WithOrigin<'_> {
+        WithOrigin(self)
+    }
+
+    /// Whether this mapping was expanded from the top-level config mapping named `name`
+    pub fn is_from(&self, name: &Path) -> bool {
+        self.origin.as_ref().is_some_and(|o| o.name == name)
+    }
+
+    pub fn created(&self) -> Option<&DateTime<Local>> {
+        self.created.as_ref()
+    }
+
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_deref()
+    }
+}
+
+/// Expands a leading `~` and makes `path` absolute (relative to the current directory)
+pub fn expand_path(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    let path = match (path.strip_prefix("~"), home_dir()) {
+        (Ok(rest), Some(home)) if rest.as_os_str().is_empty() => home,
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_owned(),
+    };
+    match path.absolutize() {
+        Ok(absolute) => absolute.into(),
+        Err(_) => path,
+    }
 }
 
+/// Displays `path` with the home directory replaced by `~`, names that aren't valid UTF-8 are
+/// displayed lossily
 pub fn pretty_path(path: &Path) -> String {
-    let str = path.to_str().unwrap();
-    if let Some(home) = &*HOME_DIR {
-        str.replacen(home, "~", 1)
-    } else {
-        str.to_owned()
+    match home_dir().as_deref().map(|home| path.strip_prefix(home)) {
+        Some(Ok(rest)) if rest.as_os_str().is_empty() => "~".to_owned(),
+        Some(Ok(rest)) => format!("~/{}", rest.display()),
+        _ => path.display().to_string(),
+    }
+}
+
+/// Restores the environment variables it was created with when dropped, for the tests that
+/// change them
+#[cfg(test)]
+pub(crate) struct EnvGuard(Vec<(&'static str, Option<std::ffi::OsString>)>);
+
+#[cfg(test)]
+impl EnvGuard {
+    pub(crate) fn new(names: &[&'static str]) -> EnvGuard {
+        EnvGuard(
+            names
+                .iter()
+                .map(|&name| (name, std::env::var_os(name)))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+impl Drop for EnvGuard {
+    fn drop(&mut self) {
+        for (name, value) in &self.0 {
+            match value {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+        }
     }
 }
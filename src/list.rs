@@ -0,0 +1,81 @@
+use crate::{cache::Cache, Mapping, Selection};
+
+#[derive(Debug, Default)]
+pub struct ListOptions {
+    /// Only list the selected links
+    selection: Selection,
+}
+
+impl ListOptions {
+    pub fn new(selection: Selection) -> Self {
+        ListOptions { selection }
+    }
+}
+
+/// Returns the cached links matching the filters in `opt`, sorted by name
+pub fn list<'a>(cache: &'a Cache, opt: &ListOptions) -> Vec<&'a Mapping> {
+    let mut links: Vec<&Mapping> = cache
+        .mappings()
+        .iter()
+        .filter(|m| opt.selection.matches(m))
+        .collect();
+    links.sort_by(|lhs, rhs| lhs.name().cmp(rhs.name()));
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::Origin;
+
+    fn mapping(name: &str, target: &str, top_level: &str) -> Mapping {
+        let origin = Origin {
+            config: PathBuf::from("/dotfiles/.george"),
+            line: 1,
+            name: PathBuf::from(top_level),
+            target: PathBuf::from("/dotfiles"),
+        };
+        Mapping::new(name, target).with_origin(Some(origin))
+    }
+
+    #[test]
+    fn filter() {
+        let cache = Cache::new(vec![
+            mapping("/home/.zshrc", "/dotfiles/.zshrc", "/home/.zshrc"),
+            mapping(
+                "/home/.config/nvim/init.lua",
+                "/dotfiles/nvim/init.lua",
+                "/home/.config",
+            ),
+            mapping(
+                "/home/.config/git/config",
+                "/dotfiles/git/config",
+                "/home/.config",
+            ),
+        ]);
+
+        let names = |paths: Vec<PathBuf>, mapping: Option<PathBuf>| -> Vec<PathBuf> {
+            let opt = ListOptions::new(Selection::new(paths, mapping));
+            list(&cache, &opt)
+                .iter()
+                .map(|m| m.name().to_owned())
+                .collect()
+        };
+
+        assert_eq!(names(vec![], None).len(), 3);
+        assert_eq!(
+            names(vec!["/home/.config/nvim".into()], None),
+            vec![Path::new("/home/.config/nvim/init.lua")]
+        );
+        assert_eq!(
+            names(vec![], Some("/home/.config".into())),
+            vec![
+                Path::new("/home/.config/git/config"),
+                Path::new("/home/.config/nvim/init.lua")
+            ]
+        );
+        assert!(names(vec!["/home/.conf".into()], None).is_empty());
+    }
+}
@@ -1,14 +1,32 @@
-use std::{fs, path::{Path, PathBuf}, io, env};
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Arc,
+};
 
-use anyhow::{Context, Result, anyhow};
-use clap::{command, Parser, Subcommand};
+use anyhow::{anyhow, bail, Context};
+use clap::{Parser, Subcommand};
 use env_logger::Builder;
 use george::{
     cache::Cache,
+    check,
     clean::{self, CleanOptions},
-    config::Config,
-    deploy::{deploy, DeployOptions},
+    config::{self, Config},
+    deploy::{self, ConflictPolicy, DeployOptions},
+    expand_path,
+    filesystem::{Fs, RealFs},
+    git, home_dir,
+    init::{self, InitOptions},
+    list::{self, ListOptions},
+    parallel, pretty_path,
+    snapshot::SymlinkPolicy,
+    staging::Staging,
+    status, watch, LinkKind, Selection,
 };
+use log::{error, info, warn};
 use std::io::Write;
 
 #[derive(Parser)]
@@ -25,16 +43,100 @@ struct Cli {
     /// Full path to the config file (including name)
     #[arg(short, long)]
     config: Option<String>,
+
+    /// Number of links created or removed in parallel [default: number of CPUs]
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// Create the links and the cache below this directory as if it were /, e.g. an image root
+    #[arg(long)]
+    root: Option<String>,
+
+    /// The home directory inside the root that ~ stands for [default: $HOME]
+    #[arg(long, requires = "root")]
+    home: Option<String>,
+
+    /// The repository config the --config of an elevated deploy was checked out from
+    #[arg(long, hide = true, requires = "config")]
+    checked_out_from: Option<String>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Deploys your dotfiles by creating symlinks
-    Deploy {},
+    Deploy {
+        /// Only deploy links below these paths
+        paths: Vec<String>,
+        /// Only deploy links created from the config mapping with this name
+        #[arg(short, long)]
+        mapping: Option<String>,
+        /// Read all target directories, even those unchanged since the last deploy
+        #[arg(long)]
+        no_snapshot: bool,
+        /// How to handle symbolic links inside the dotfiles: follow, replicate or skip
+        #[arg(long, default_value_t)]
+        symlinks: SymlinkPolicy,
+        /// Also create links outside of the allowed roots of the config [default: $HOME]
+        #[arg(long)]
+        allow_outside_home: bool,
+        /// Deploy the system mappings as root using the sudo command of the config
+        #[arg(long)]
+        system: bool,
+        /// Deploy this revision of the dotfiles repository, checked out into a worktree
+        #[arg(long)]
+        rev: Option<String>,
+        /// What to do with existing files in the way of links: backup, skip or ask
+        #[arg(long, default_value_t)]
+        conflicts: ConflictPolicy,
+    },
     /// Removes all (cached) created symlinks
-    Clean {},
+    Clean {
+        /// Only remove links below these paths
+        paths: Vec<String>,
+        /// Only remove links created from the config mapping with this name
+        #[arg(short, long)]
+        mapping: Option<String>,
+        /// Remove the system links as root instead of the user links
+        #[arg(long)]
+        system: bool,
+    },
     /// Does a clean and then a deploy
     Redeploy {},
+    /// Fast-forwards the dotfiles repository, shows the changed links and deploys them
+    Sync {},
+    /// Clones a dotfiles repository and deploys it, asking before replacing existing files
+    Clone {
+        /// The URL or path of the repository
+        url: String,
+        /// The directory to clone into [default: the name of the repository]
+        dir: Option<String>,
+    },
+    /// Lists all (cached) created symlinks
+    List {
+        /// Only list links below this path
+        #[arg(short, long)]
+        prefix: Option<String>,
+        /// Only list links created from the config mapping with this name
+        #[arg(short, long)]
+        mapping: Option<String>,
+    },
+    /// Shows whether the (cached) created symlinks still point to their targets
+    Status {},
+    /// Validates the config without creating any links
+    Check {},
+    /// Deploys and then re-deploys whenever the dotfiles change
+    Watch {},
+    /// Creates a dotfiles directory with a starter config
+    Init {
+        /// The dotfiles directory to create [default: current directory]
+        dir: Option<String>,
+        /// Copy common dotfiles found in your home directory into the dotfiles directory
+        #[arg(short, long)]
+        adopt: bool,
+        /// Don't make this the config used outside of the dotfiles directory
+        #[arg(long)]
+        no_register: bool,
+    },
 }
 
 fn main() -> anyhow::Result<()> {
@@ -48,41 +150,431 @@ fn main() -> anyhow::Result<()> {
         .init();
 
     let cli = Cli::parse();
+    let jobs = cli.jobs.unwrap_or_else(parallel::default_jobs);
+    let staging = staging(&cli.root, &cli.home)?;
+    let fs: Arc<dyn Fs> = match &staging {
+        Some(staging) => Arc::new(staging.fs()),
+        None => Arc::new(RealFs),
+    };
 
     match cli.command {
-        Commands::Deploy {} => {
-            let path = if let Some(path) = &cli.config {
-                PathBuf::from(path)
-            } else {
-                find_config().context("Failed to find config")?
+        Commands::Deploy {
+            paths,
+            mapping,
+            no_snapshot,
+            symlinks,
+            allow_outside_home,
+            system,
+            rev,
+            conflicts,
+        } => {
+            // The revision is checked out and its worktree removed again by the user, never by
+            // the elevated george
+            let build = |path: PathBuf| -> anyhow::Result<Config> {
+                Ok(match &rev {
+                    Some(rev) => Config::build(checkout(&path, rev)?)?.checked_out(path),
+                    None => Config::build(path)?,
+                })
             };
-            let cfg = Config::build(path)?;
-            let cache = Cache::load().unwrap_or_default();
-            let new_cache = deploy(cache, DeployOptions::new(!cli.keep_dir), cfg);
-            new_cache.save().expect("Failed to save cache");
+            if system && needs_root(staging.as_ref()) {
+                let cfg = build(config_path(&cli.config, None)?)?;
+                elevate(cfg.sudo(), Some(&cfg))?;
+                remove_unused_worktrees(cfg.source(), staging.as_ref(), fs.as_ref());
+                return Ok(());
+            }
+            let cache = load_cache(staging.as_ref(), system);
+            let mut cfg = build(config_path(&cli.config, Some(&cache))?)?;
+            if let Some(source) = &cli.checked_out_from {
+                cfg = cfg.checked_out(expand_path(source));
+            }
+            if let Some(staging) = &staging {
+                cfg = cfg.staged(staging);
+            }
+            let system_mappings = cfg.mappings().iter().filter(|m| m.options().system);
+            let system_mappings = system_mappings.count();
+            let opt = DeployOptions::new(!cli.keep_dir)
+                .with_selection(selection(&paths, &mapping, staging.as_ref()))
+                .with_snapshot(!no_snapshot)
+                .with_symlinks(symlinks)
+                .with_jobs(jobs)
+                .with_fs(fs.clone())
+                .with_outside_home(allow_outside_home)
+                .with_system(system)
+                .with_conflicts(conflicts);
+            run_deploy(cache, opt, cfg, staging.as_ref(), system, fs.as_ref())?;
+            if !system && system_mappings > 0 {
+                info!("skipped {system_mappings} system mapping(s), deploy them with george deploy --system");
+            }
+        }
+        Commands::Clone { url, dir } => {
+            let dir = expand_path(dir.as_deref().unwrap_or(git::clone_dir(&url)));
+            if dir.exists() {
+                bail!("{} already exists", pretty_path(&dir));
+            }
+            git::clone(&url, &dir).with_context(|| format!("Failed to clone {url}"))?;
+            info!("cloned {url} into {}", pretty_path(&dir));
+
+            // Only the config of the clone itself is deployed, not one found elsewhere
+            let path = dir.join(".george");
+            if !path.is_file() {
+                bail!("{} has no .george config", pretty_path(&dir));
+            }
+            let cache = load_cache(staging.as_ref(), false);
+            let mut cfg = Config::build(path)?;
+            if let Some(staging) = &staging {
+                cfg = cfg.staged(staging);
+            }
+            let opt = DeployOptions::new(!cli.keep_dir)
+                .with_jobs(jobs)
+                .with_fs(fs.clone())
+                .with_conflicts(ConflictPolicy::Ask);
+            run_deploy(cache, opt, cfg, staging.as_ref(), false, fs.as_ref())?;
+        }
+        Commands::Sync {} => {
+            let cache = load_cache(staging.as_ref(), false);
+            let path = config_path(&cli.config, Some(&cache))?;
+            let dir = expand_path(path.parent().unwrap());
+            let before = git::head(&dir);
+            git::pull(&dir).with_context(|| {
+                format!(
+                    "Failed to fast-forward {}, merge the changes and deploy manually",
+                    pretty_path(&dir)
+                )
+            })?;
+            match git::head(&dir) {
+                head if head == before => info!("already up to date"),
+                Some(head) => info!("updated to commit {head}"),
+                None => {}
+            }
+
+            let mut cfg = Config::build(path)?;
+            if let Some(staging) = &staging {
+                cfg = cfg.staged(staging);
+            }
+            let opt = DeployOptions::new(!cli.keep_dir)
+                .with_jobs(jobs)
+                .with_fs(fs.clone());
+            let changes = deploy::changes(&cache, &opt, &cfg);
+            for link in changes.added.iter() {
+                info!("new link {link}");
+            }
+            for link in changes.removed.iter() {
+                info!("removed link {link}");
+            }
+            for (old, new) in changes.changed.iter() {
+                info!("changed link {old} to {new}");
+            }
+            if changes.is_empty() {
+                info!("no links changed");
+            }
+            run_deploy(cache, opt, cfg, staging.as_ref(), false, fs.as_ref())?;
         }
-        Commands::Clean {} => {
-            let cache = Cache::load().unwrap_or_default();
-            let new_cache = clean::clean(cache, CleanOptions::new(!cli.keep_dir));
-            new_cache.save().expect("Failed to save cache");
+        Commands::Clean {
+            paths,
+            mapping,
+            system,
+        } => {
+            if system && needs_root(staging.as_ref()) {
+                let cfg = config_path(&cli.config, None).ok().map(Config::build);
+                let cfg = cfg.transpose()?;
+                let sudo = cfg.as_ref().map_or("sudo", Config::sudo);
+                return elevate(sudo, cfg.as_ref());
+            }
+            let cache = load_cache(staging.as_ref(), system);
+            let mut opt = CleanOptions::new(!cli.keep_dir)
+                .with_selection(selection(&paths, &mapping, staging.as_ref()))
+                .with_jobs(jobs)
+                .with_fs(fs)
+                .with_system(system);
+            // Cleaning works without a config, it's only needed for the hooks, which aren't run
+            // for the system links
+            match config_path(&cli.config, Some(&cache)) {
+                Ok(path) if !system => match Config::build(path) {
+                    Ok(cfg) => opt = opt.with_hooks(&cfg),
+                    Err(e) => warn!("{}, cleaning without running hooks", e),
+                },
+                _ => {}
+            }
+            let (new_cache, report) = clean::clean(cache, opt);
+            report.log();
+            save_cache(&new_cache, staging.as_ref(), system)?;
+            if !report.hooks.is_empty() {
+                bail!("{} hook(s) failed", report.hooks.len());
+            }
         }
         Commands::Redeploy {} => {}
+        Commands::List { prefix, mapping } => {
+            let cache = load_cache(staging.as_ref(), false);
+            let prefix: Vec<String> = prefix.into_iter().collect();
+            let opt = ListOptions::new(selection(&prefix, &mapping, staging.as_ref()));
+            for link in list::list(&cache, &opt) {
+                let created = link.created().map_or("unknown".to_owned(), |c| {
+                    c.format("%Y-%m-%d %H:%M:%S").to_string()
+                });
+                let origin = link
+                    .origin()
+                    .map_or("unknown origin".to_owned(), |o| o.to_string());
+                let target = match link.kind() {
+                    LinkKind::Directory => String::new(),
+                    _ => format!(" -> {}", pretty_path(link.target())),
+                };
+                println!(
+                    "{}{} ({}, created {}, from {})",
+                    pretty_path(link.name()),
+                    target,
+                    link.kind(),
+                    created,
+                    origin
+                );
+            }
+        }
+        Commands::Check {} => {
+            let path = config_path(&cli.config, None)?;
+            let path = expand_path(&path);
+            let content = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", pretty_path(&path)))?;
+            let home = home_dir();
+
+            let problems = check::check(&content, &path, home.as_deref());
+            for problem in problems.iter() {
+                if problem.is_error() {
+                    error!("error: {}", problem);
+                } else {
+                    warn!("warning: {}", problem);
+                }
+            }
+
+            let errors = problems.iter().filter(|p| p.is_error()).count();
+            if errors > 0 {
+                bail!("{} has {} error(s)", pretty_path(&path), errors);
+            }
+        }
+        Commands::Watch {} => {
+            if staging.is_some() {
+                bail!("--root can't be used with watch");
+            }
+            let path = config_path(&cli.config, None)?;
+            let path = expand_path(&path);
+            watch::watch(path, !cli.keep_dir, jobs).map_err(|e| anyhow!("{e}"))?;
+        }
+        Commands::Init {
+            dir,
+            adopt,
+            no_register,
+        } => {
+            let dir = expand_path(dir.as_deref().unwrap_or("."));
+            let home = home_dir();
+            if adopt && home.is_none() {
+                bail!("Failed to expand $HOME, cannot adopt dotfiles");
+            }
+            let opt = InitOptions::new(dir)
+                .with_adopt(home.filter(|_| adopt))
+                .with_register(!no_register);
+            let path = init::init(opt)?;
+            info!("edit {} and run george deploy", pretty_path(&path));
+        }
+        Commands::Status {} => {
+            let cache = load_cache(staging.as_ref(), false);
+            if let Some(commit) = cache.commit() {
+                info!("deployed from commit {commit}");
+            }
+            let dir = cache.config().and_then(Path::parent);
+            let changed = dir.and_then(|dir| git::uncommitted(dir).ok());
+            for link in status::uncommitted(&cache, &changed.unwrap_or_default(), fs.as_ref()) {
+                warn!("{link}: target has uncommitted changes");
+            }
+            for (link, status) in status::status(&cache, fs.as_ref()) {
+                let origin = link
+                    .origin()
+                    .map_or("unknown origin".to_owned(), |o| o.to_string());
+                println!("{}: {} (from {})", link, status, origin);
+            }
+        }
     }
     Ok(())
 }
 
-pub fn find_config() -> Option<PathBuf> {
-    let cwd = env::current_dir().unwrap();
-    let config = cwd.join(".george");
-    if config.exists() {
-        return Some(config);
+fn config_path(config: &Option<String>, cache: Option<&Cache>) -> anyhow::Result<PathBuf> {
+    // The path is recorded in the cache, so it has to stay valid from other directories
+    if let Some(path) = config {
+        let path = expand_path(path);
+        return Ok(path.canonicalize().unwrap_or(path));
+    }
+
+    let cwd = env::current_dir()?;
+    let last = || match cache {
+        Some(cache) => cache.config().map(Path::to_owned),
+        None => Cache::load().ok()?.config().map(Path::to_owned),
+    };
+    Ok(config::find(&cwd, last)?)
+}
+
+fn selection(paths: &[String], mapping: &Option<String>, staging: Option<&Staging>) -> Selection {
+    let name = |path: &str| match staging {
+        Some(staging) => staging.name(&expand_path(path)),
+        None => expand_path(path),
+    };
+    let paths = paths.iter().map(|p| name(p)).collect();
+    Selection::new(paths, mapping.as_deref().map(name))
+}
+
+fn staging(root: &Option<String>, home: &Option<String>) -> anyhow::Result<Option<Staging>> {
+    let Some(root) = root else {
+        return Ok(None);
+    };
+    let root = expand_path(root);
+    fs::create_dir_all(&root)
+        .with_context(|| format!("Failed to create {}", pretty_path(&root)))?;
+    let home = home.as_deref().map(PathBuf::from);
+    if home.as_ref().is_some_and(|h| !h.is_absolute()) {
+        bail!("--home has to be an absolute path inside the root");
+    }
+    Ok(Some(Staging::new(root.canonicalize()?, home)))
+}
+
+/// Deploys `cfg` and saves the new cache, then removes the worktrees of earlier revisions that
+/// no link points into anymore
+fn run_deploy(
+    cache: Cache,
+    opt: DeployOptions,
+    cfg: Config,
+    staging: Option<&Staging>,
+    system: bool,
+    fs: &dyn Fs,
+) -> anyhow::Result<()> {
+    let source = cfg.source().to_owned();
+    let (new_cache, report) = deploy::deploy(cache, opt, cfg);
+    report.log();
+    if new_cache.is_changed() {
+        save_cache(&new_cache, staging, system)?;
+    } else {
+        info!("nothing changed");
+    }
+
+    // The system deploy may run as root, which would see the user cache of root
+    if !system {
+        remove_unused_worktrees(&source, staging, fs);
     }
+    if !report.hooks.is_empty() {
+        bail!("{} hook(s) failed", report.hooks.len());
+    }
+    Ok(())
+}
+
+/// Removes the worktrees of the repository containing `config` that neither a user nor a system
+/// link points into anymore. Nothing is removed if one of the caches can't be read.
+fn remove_unused_worktrees(config: &Path, staging: Option<&Staging>, fs: &dyn Fs) {
+    let Some(repo) = config.parent().and_then(git::toplevel) else {
+        return;
+    };
+    let mut used = Vec::new();
+    for system in [false, true] {
+        let dir = cache_dir(staging, system).ok();
+        let Some(cache) = dir.and_then(|dir| read_cache(&dir)) else {
+            return;
+        };
+        used.extend(cache.mappings().iter().map(|m| fs.host_path(m.target())));
+    }
+    if let Err(e) = git::remove_unused_worktrees(&repo, &used) {
+        warn!("failed to remove unused worktrees: {e}");
+    }
+}
+
+/// Checks out `rev` of the repository containing `config` and returns the path of the config
+/// inside the worktree
+fn checkout(config: &Path, rev: &str) -> anyhow::Result<PathBuf> {
+    let config = expand_path(config);
+    let config = config
+        .canonicalize()
+        .with_context(|| format!("Failed to read {}", pretty_path(&config)))?;
+    let dir = config.parent().unwrap();
+    let repo = git::toplevel(dir)
+        .with_context(|| format!("{} isn't inside a git repository", pretty_path(dir)))?;
+    let worktree =
+        git::checkout(&repo, rev).with_context(|| format!("Failed to check out {rev}"))?;
+    info!("deploying {rev} from {}", pretty_path(&worktree));
+    Ok(worktree.join(config.strip_prefix(&repo)?))
+}
+
+/// The directory of the user or system cache, inside the staging root if there is one
+fn cache_dir(staging: Option<&Staging>, system: bool) -> anyhow::Result<PathBuf> {
+    Ok(match (staging, system) {
+        (Some(staging), false) => staging.cache_dir(),
+        (Some(staging), true) => staging.system_cache_dir(),
+        (None, false) => Cache::default_dir().map_err(|e| anyhow!("{e}"))?,
+        (None, true) => Cache::system_dir(),
+    })
+}
+
+/// Loads the cache in `dir`, `None` if it exists but can't be read
+fn read_cache(dir: &Path) -> Option<Cache> {
+    if dir.exists() && fs::read_dir(dir).is_err() {
+        return None;
+    }
+    Cache::load_from(dir).ok()
+}
+
+fn load_cache(staging: Option<&Staging>, system: bool) -> Cache {
+    let cache = cache_dir(staging, system).ok();
+    cache
+        .and_then(|dir| Cache::load_from(&dir).ok())
+        .unwrap_or_default()
+}
+
+fn save_cache(cache: &Cache, staging: Option<&Staging>, system: bool) -> anyhow::Result<()> {
+    cache
+        .save_to(&cache_dir(staging, system)?)
+        .map_err(|e| anyhow!("Failed to save cache: {e}"))
+}
+
+/// Whether system links have to be created by an elevated george, links inside a staging root
+/// are created by the user
+fn needs_root(staging: Option<&Staging>) -> bool {
+    staging.is_none() && !nix::unistd::geteuid().is_root()
+}
+
+/// Runs george again with the same arguments through `sudo`, passing the config explicitly as
+/// the elevated one would search for it in the home directory of root. A checked out revision
+/// is passed as its config in the worktree.
+fn elevate(sudo: &str, config: Option<&Config>) -> anyhow::Result<()> {
+    let mut words = sudo.split_whitespace();
+    let program = words.next().context("The sudo command is empty")?;
+    let mut command = Command::new(program);
+    command.args(words).arg(env::current_exe()?);
+    if let Some(config) = config {
+        command.arg("--config").arg(expand_path(config.path()));
+        if let Some(source) = config.checked_out_from() {
+            command.arg("--checked-out-from").arg(source);
+        }
+    }
+    command.args(forwarded_args());
+
+    info!("running the system step with {program}");
+    let status = command
+        .status()
+        .with_context(|| format!("Failed to run {program}"))?;
+    if !status.success() {
+        bail!("The system step failed ({status})");
+    }
+    Ok(())
+}
 
-    for parent in cwd.ancestors() {
-        let config = parent.join(".george");
-        if config.exists() {
-            return Some(config);
+/// The arguments george was called with apart from the config and the revision, which
+/// `elevate` passes as the config checked out already
+fn forwarded_args() -> Vec<OsString> {
+    let mut forwarded = Vec::new();
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-c" | "--config" | "--rev" | "--checked-out-from") => {
+                args.next();
+            }
+            Some(arg)
+                if ["--config=", "--rev=", "--checked-out-from="]
+                    .iter()
+                    .any(|prefix| arg.starts_with(prefix)) => {}
+            _ => forwarded.push(arg),
         }
     }
-    None
+    forwarded
 }
@@ -0,0 +1,255 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use log::{error, info};
+use nix::unistd::{Group, User};
+
+use crate::{filesystem::Fs, pretty_path, secret, LinkKind, Mapping};
+
+/// The permissions a path should have, `None` means they aren't managed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Wanted {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+/// A difference between the permissions a path should have and those it has
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Drift {
+    Mode {
+        path: PathBuf,
+        expected: u32,
+        actual: u32,
+    },
+    Owner {
+        path: PathBuf,
+        expected: u32,
+        actual: u32,
+    },
+    Group {
+        path: PathBuf,
+        expected: u32,
+        actual: u32,
+    },
+}
+
+impl Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Drift::Mode {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} has mode {:o} instead of {:o}",
+                pretty_path(path),
+                actual,
+                expected
+            ),
+            Drift::Owner {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} is owned by user {} instead of {}",
+                pretty_path(path),
+                actual,
+                expected
+            ),
+            Drift::Group {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} is owned by group {} instead of {}",
+                pretty_path(path),
+                actual,
+                expected
+            ),
+        }
+    }
+}
+
+/// Resolves a user name or id to the user id
+pub(crate) fn uid(owner: &str) -> Option<u32> {
+    match owner.parse() {
+        Ok(uid) => Some(uid),
+        Err(_) => User::from_name(owner).ok()?.map(|u| u.uid.as_raw()),
+    }
+}
+
+/// Resolves a group name or id to the group id
+pub(crate) fn gid(group: &str) -> Option<u32> {
+    match group.parse() {
+        Ok(gid) => Some(gid),
+        Err(_) => Group::from_name(group).ok()?.map(|g| g.gid.as_raw()),
+    }
+}
+
+/// The existing paths whose permissions `mapping` manages: the deployed file or directory and
+/// the directories containing the link below the name of the config mapping it came from
+pub fn managed(mapping: &Mapping, fs: &dyn Fs) -> Vec<(PathBuf, Wanted)> {
+    let options = mapping.options();
+    let uid = options.owner.as_deref().and_then(uid);
+    let gid = options.group.as_deref().and_then(gid);
+    let mut paths = Vec::new();
+
+    let deployed = match mapping.kind() {
+        LinkKind::Directory => Some((mapping.name(), options.mode)),
+        LinkKind::Secret => Some((mapping.name(), options.mode.or(Some(secret::MODE)))),
+        LinkKind::Symlink if fs.is_file(mapping.target()) => Some((mapping.target(), options.mode)),
+        _ => None,
+    };
+    if let Some((path, mode)) = deployed.filter(|(path, _)| fs.exists(path)) {
+        paths.push((path.to_owned(), Wanted { mode, uid, gid }));
+    }
+
+    let mode = options.dir_mode;
+    for dir in directories(mapping, fs) {
+        if fs.is_dir(&dir) {
+            paths.push((dir, Wanted { mode, uid, gid }));
+        }
+    }
+
+    paths.retain(|(_, wanted)| *wanted != Wanted::default());
+    paths
+}
+
+/// The directories containing the link of `mapping` below the name of its config mapping. The
+/// directory a file mapping is linked into isn't managed, it's usually shared with other files.
+fn directories(mapping: &Mapping, fs: &dyn Fs) -> Vec<PathBuf> {
+    let name = mapping.name();
+    if mapping.kind() == LinkKind::Directory {
+        return Vec::new();
+    }
+    if fs.is_dir(mapping.target()) {
+        // A directory mapping that isn't expanded yet
+        return vec![name.to_owned()];
+    }
+
+    let top = mapping.origin().map_or(name, |o| o.name.as_path());
+    name.ancestors()
+        .skip(1)
+        .take_while(|dir| dir.starts_with(top))
+        .map(Path::to_owned)
+        .collect()
+}
+
+/// Returns how the permissions of the paths managed by `mapping` differ from its options
+pub fn drift(mapping: &Mapping, fs: &dyn Fs) -> Vec<Drift> {
+    managed(mapping, fs)
+        .into_iter()
+        .flat_map(|(path, wanted)| path_drift(&path, wanted, fs))
+        .collect()
+}
+
+fn path_drift(path: &Path, wanted: Wanted, fs: &dyn Fs) -> Vec<Drift> {
+    let Ok(metadata) = fs.metadata(path) else {
+        return Vec::new();
+    };
+
+    let mut drift = Vec::new();
+    let actual = metadata.mode;
+    if let Some(expected) = wanted.mode.filter(|mode| *mode != actual) {
+        let path = path.to_owned();
+        drift.push(Drift::Mode {
+            path,
+            expected,
+            actual,
+        });
+    }
+    if let Some(expected) = wanted.uid.filter(|uid| *uid != metadata.uid) {
+        let (path, actual) = (path.to_owned(), metadata.uid);
+        drift.push(Drift::Owner {
+            path,
+            expected,
+            actual,
+        });
+    }
+    if let Some(expected) = wanted.gid.filter(|gid| *gid != metadata.gid) {
+        let (path, actual) = (path.to_owned(), metadata.gid);
+        drift.push(Drift::Group {
+            path,
+            expected,
+            actual,
+        });
+    }
+    drift
+}
+
+/// Applies the permissions of all `mappings`, each path is changed at most once
+pub(crate) fn apply<'a>(mappings: impl Iterator<Item = &'a Mapping>, fs: &dyn Fs) {
+    let mut paths: BTreeMap<PathBuf, (Wanted, &Mapping)> = BTreeMap::new();
+    for mapping in mappings {
+        for (path, wanted) in managed(mapping, fs) {
+            paths.entry(path).or_insert((wanted, mapping));
+        }
+    }
+
+    for (path, (wanted, mapping)) in paths {
+        fix(&path, wanted, mapping, fs);
+    }
+}
+
+/// Applies the directory permissions of `mapping` to `dir`, which was created for its link
+pub(crate) fn apply_created(dir: &Path, mapping: &Mapping, fs: &dyn Fs) {
+    let options = mapping.options();
+    let wanted = Wanted {
+        mode: options.dir_mode,
+        uid: options.owner.as_deref().and_then(uid),
+        gid: options.group.as_deref().and_then(gid),
+    };
+    fix(dir, wanted, mapping, fs);
+}
+
+fn fix(path: &Path, wanted: Wanted, mapping: &Mapping, fs: &dyn Fs) {
+    for drift in path_drift(path, wanted, fs) {
+        let result = match drift {
+            Drift::Mode { expected, .. } => fs.set_mode(path, expected),
+            Drift::Owner { expected, .. } => fs.chown(path, Some(expected), None),
+            Drift::Group { expected, .. } => fs.chown(path, None, Some(expected)),
+        };
+        match result {
+            Ok(()) => info!("{}: fixed, {}", mapping, drift),
+            Err(e) => error!("{}: {}, failed to fix: {}", mapping.with_source(), drift, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{filesystem::MemoryFs, Origin};
+
+    #[test]
+    fn directories_below_mapping() {
+        let fs = MemoryFs::new();
+        let origin = |name: &str| Origin {
+            config: PathBuf::from("/dotfiles/.george"),
+            line: 1,
+            name: PathBuf::from(name),
+            target: PathBuf::from("/dotfiles/missing"),
+        };
+
+        let file = Mapping::new("/home/me/.ssh/config", "/dotfiles/missing/config")
+            .with_origin(Some(origin("/home/me/.ssh/config")));
+        assert!(directories(&file, &fs).is_empty());
+
+        let expanded = Mapping::new("/home/me/.ssh/keys/id", "/dotfiles/missing/keys/id")
+            .with_origin(Some(origin("/home/me/.ssh")));
+        assert_eq!(
+            directories(&expanded, &fs),
+            vec![
+                PathBuf::from("/home/me/.ssh/keys"),
+                PathBuf::from("/home/me/.ssh")
+            ]
+        );
+    }
+}
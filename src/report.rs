@@ -0,0 +1,116 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use log::{error, warn};
+
+use crate::pretty_path;
+
+/// Why an entry wasn't deployed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The target of the mapping doesn't exist
+    TargetMissing,
+    /// A FIFO or socket, only linked if the mapping sets `special = true`
+    Special,
+    /// Neither a regular file, directory, symbolic link nor special file
+    Unhandled,
+    /// A symbolic link, skipped due to the symlink policy
+    Symlink,
+    /// A symbolic link to a directory containing it
+    Cycle(PathBuf),
+    /// A symbolic link whose target doesn't exist
+    Dangling,
+    /// The name lies outside of the allowed roots
+    OutsideRoots,
+    /// The link would back up or replace the dotfiles directory or its config
+    Protected,
+    /// A file is in the way of the link and was kept due to the conflict policy
+    Conflict,
+}
+
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::TargetMissing => write!(f, "target does not exist"),
+            SkipReason::Special => write!(f, "special file, set 'special = true' to link it"),
+            SkipReason::Unhandled => write!(f, "file type isn't handled"),
+            SkipReason::Symlink => write!(f, "symbolic link"),
+            SkipReason::Cycle(real) => {
+                write!(f, "symbolic link to {} forms a cycle", pretty_path(real))
+            }
+            SkipReason::Dangling => write!(f, "symbolic link target doesn't exist"),
+            SkipReason::OutsideRoots => write!(
+                f,
+                "outside of the allowed roots, add an 'allowed_root' or pass --allow-outside-home"
+            ),
+            SkipReason::Conflict => write!(f, "already exists and was kept"),
+            SkipReason::Protected => {
+                write!(f, "would replace the dotfiles directory or its config")
+            }
+        }
+    }
+}
+
+/// An entry that wasn't deployed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Skipped {
+    pub path: PathBuf,
+    pub reason: SkipReason,
+}
+
+impl Display for Skipped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: skipped, {}", pretty_path(&self.path), self.reason)
+    }
+}
+
+/// A hook that didn't run successfully
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookFailure {
+    /// Which hook failed, e.g. `post_deploy` or `on_change of [~/.fonts -> fonts]`
+    pub hook: String,
+    pub command: String,
+    pub error: String,
+}
+
+impl Display for HookFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} hook '{}' failed: {}",
+            self.hook, self.command, self.error
+        )
+    }
+}
+
+/// What happened during a deploy or clean apart from the links created or removed
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub skipped: Vec<Skipped>,
+    pub hooks: Vec<HookFailure>,
+}
+
+impl Report {
+    pub fn skip(&mut self, path: &Path, reason: SkipReason) {
+        self.skipped.push(Skipped {
+            path: path.to_owned(),
+            reason,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.skipped.is_empty() && self.hooks.is_empty()
+    }
+
+    /// Logs skipped entries as warnings and failed hooks as errors
+    pub fn log(&self) {
+        for skipped in self.skipped.iter() {
+            warn!("{}", skipped);
+        }
+        for failure in self.hooks.iter() {
+            error!("{}", failure);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::{
+    io,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use chrono::{DateTime, Local};
+use sha2::{Digest, Sha256};
+
+use crate::{filesystem::Fs, Mapping};
+
+/// The mode of decrypted copies unless the mapping sets another one
+pub const MODE: u32 = 0o600;
+
+/// The hex encoded SHA-256 hash of `content`
+pub fn hash(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// The hash of the file at `path`, `None` if it can't be read
+pub fn file_hash(path: &Path, fs: &dyn Fs) -> Option<String> {
+    fs.read(path).ok().map(|content| hash(&content))
+}
+
+/// Decrypts the target of `mapping` into a new file at its name that only the user can read,
+/// returns the hash of the decrypted content
+pub(crate) fn decrypt(mapping: &Mapping, fs: &dyn Fs) -> io::Result<String> {
+    let Some(command) = mapping.options().decrypt.as_deref() else {
+        return Err(io::Error::other("no decrypt command set"));
+    };
+    let target = fs.host_path(&fs.canonicalize(mapping.target())?);
+
+    // The target is passed as argument so that it doesn't have to be quoted
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{command} \"$1\""))
+        .arg("george")
+        .arg(target)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "'{command}' failed: {}",
+            output.status
+        )));
+    }
+
+    let mode = mapping.options().mode.unwrap_or(MODE);
+    fs.create_file(mapping.name(), &output.stdout, mode)?;
+    Ok(hash(&output.stdout))
+}
+
+/// Whether the encrypted target was modified after the secret was decrypted
+pub(crate) fn is_outdated(
+    mapping: &Mapping,
+    created: Option<&DateTime<Local>>,
+    fs: &dyn Fs,
+) -> bool {
+    match (fs.metadata(mapping.target()), created) {
+        (Ok(metadata), Some(created)) => DateTime::<Local>::from(metadata.modified) > *created,
+        _ => true,
+    }
+}
+
+/// Overwrites the decrypted copy with zeros before removing it
+pub(crate) fn remove(path: &Path, fs: &dyn Fs) -> io::Result<()> {
+    let len = fs.symlink_metadata(path)?.len;
+    fs.overwrite(path, &vec![0; len as usize])?;
+    fs.remove_file(path)
+}
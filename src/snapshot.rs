@@ -0,0 +1,390 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    filesystem::{FileKind, Fs},
+    report::{Report, SkipReason},
+    LinkKind,
+};
+
+/// Directories modified this recently are read again on the next deploy, as they might change
+/// again without getting a new modification time from the coarse filesystem clock
+const RACY: Duration = Duration::from_secs(2);
+
+/// How symbolic links inside the dotfiles are expanded
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Link to the symbolic link like to a file and expand linked directories like directories
+    #[default]
+    Follow,
+    /// Create a symbolic link with the same content
+    Replicate,
+    /// Ignore symbolic links
+    Skip,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "replicate" => Ok(SymlinkPolicy::Replicate),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            _ => Err(format!(
+                "unknown symlink policy '{s}', expected follow, replicate or skip"
+            )),
+        }
+    }
+}
+
+impl Display for SymlinkPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SymlinkPolicy::Follow => write!(f, "follow"),
+            SymlinkPolicy::Replicate => write!(f, "replicate"),
+            SymlinkPolicy::Skip => write!(f, "skip"),
+        }
+    }
+}
+
+/// The contents of a directory at the time it was last read
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct DirSnapshot {
+    modified: SystemTime,
+    files: Vec<OsString>,
+    dirs: Vec<OsString>,
+    #[serde(default)]
+    symlinks: Vec<OsString>,
+    /// FIFOs and sockets
+    #[serde(default)]
+    special: Vec<OsString>,
+}
+
+/// The modification times and contents of the directories below the targets, used to skip
+/// reading directories that didn't change since the last deploy
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    dirs: HashMap<PathBuf, DirSnapshot>,
+}
+
+/// The state of a single walk
+struct Walk<'a> {
+    fs: &'a dyn Fs,
+    previous: Option<&'a Snapshot>,
+    symlinks: SymlinkPolicy,
+    /// Whether FIFOs and sockets are linked
+    special: bool,
+    /// The real paths of the directories currently walked, used to detect cycles
+    stack: Vec<PathBuf>,
+    entries: Vec<(PathBuf, LinkKind)>,
+    report: Report,
+}
+
+impl Snapshot {
+    /// Lists all entries below `dir` that should be linked, relative to `dir`, together with how
+    /// to link them and records the directories read. Directories that weren't modified since
+    /// they were recorded in `previous` are not read again. The entries that are skipped are
+    /// added to `report`.
+    pub fn walk(
+        &mut self,
+        dir: &Path,
+        previous: Option<&Snapshot>,
+        symlinks: SymlinkPolicy,
+        special: bool,
+        report: &mut Report,
+        fs: &dyn Fs,
+    ) -> Vec<(PathBuf, LinkKind)> {
+        let Ok(real) = fs.canonicalize(dir) else {
+            return Vec::new();
+        };
+
+        let mut walk = Walk {
+            fs,
+            previous,
+            symlinks,
+            special,
+            stack: vec![real],
+            entries: Vec::new(),
+            report: Report::default(),
+        };
+        self.walk_into(dir, Path::new(""), &mut walk);
+        report.skipped.extend(walk.report.skipped);
+        walk.entries
+    }
+
+    /// Walks `dir`, which is at `rel` relative to the directory the walk started in
+    fn walk_into(&mut self, dir: &Path, rel: &Path, walk: &mut Walk) {
+        let Ok(modified) = walk.fs.metadata(dir).map(|m| m.modified) else {
+            return;
+        };
+
+        let snapshot = match walk.previous.and_then(|p| p.dirs.get(dir)) {
+            Some(snapshot) if snapshot.modified == modified => snapshot.to_owned(),
+            _ => match read_dir(dir, modified, walk.fs) {
+                Some(snapshot) => snapshot,
+                None => return,
+            },
+        };
+
+        let files = snapshot.files.iter().map(|f| rel.join(f));
+        walk.entries.extend(files.map(|f| (f, LinkKind::Symlink)));
+        for subdir in snapshot.dirs.iter() {
+            let real = walk.stack.last().unwrap().join(subdir);
+            walk.stack.push(real);
+            self.walk_into(&dir.join(subdir), &rel.join(subdir), walk);
+            walk.stack.pop();
+        }
+        for symlink in snapshot.symlinks.iter() {
+            self.walk_symlink(&dir.join(symlink), &rel.join(symlink), walk);
+        }
+        for special in snapshot.special.iter() {
+            walk.special_file(&dir.join(special), &rel.join(special));
+        }
+        if SystemTime::now()
+            .duration_since(modified)
+            .is_ok_and(|age| age >= RACY)
+        {
+            self.dirs.insert(dir.to_owned(), snapshot);
+        }
+    }
+
+    fn walk_symlink(&mut self, path: &Path, rel: &Path, walk: &mut Walk) {
+        match walk.symlinks {
+            SymlinkPolicy::Skip => walk.report.skip(path, SkipReason::Symlink),
+            SymlinkPolicy::Replicate => walk.entries.push((rel.to_owned(), LinkKind::Replica)),
+            SymlinkPolicy::Follow => match (walk.fs.metadata(path), walk.fs.canonicalize(path)) {
+                (Ok(metadata), _) if metadata.is_file() => {
+                    walk.entries.push((rel.to_owned(), LinkKind::Symlink))
+                }
+                (Ok(metadata), Ok(real)) if metadata.is_dir() => {
+                    if walk.stack.iter().any(|dir| dir.starts_with(&real)) {
+                        walk.report.skip(path, SkipReason::Cycle(real));
+                        return;
+                    }
+                    walk.stack.push(real);
+                    self.walk_into(path, rel, walk);
+                    walk.stack.pop();
+                }
+                (Ok(metadata), _) if metadata.kind == FileKind::Special => {
+                    walk.special_file(path, rel)
+                }
+                (Ok(_), _) => walk.report.skip(path, SkipReason::Unhandled),
+                (Err(_), _) => walk.report.skip(path, SkipReason::Dangling),
+            },
+        }
+    }
+
+    /// Merges the directories recorded in `other` into this snapshot
+    pub fn extend(&mut self, other: Snapshot) {
+        self.dirs.extend(other.dirs);
+    }
+
+    /// Removes the record of `dir`, so that it's read again
+    pub fn forget(&mut self, dir: &Path) {
+        self.dirs.remove(dir);
+    }
+}
+
+impl Walk<'_> {
+    fn special_file(&mut self, path: &Path, rel: &Path) {
+        if self.special {
+            self.entries.push((rel.to_owned(), LinkKind::Symlink));
+        } else {
+            self.report.skip(path, SkipReason::Special);
+        }
+    }
+}
+
+fn read_dir(dir: &Path, modified: SystemTime, fs: &dyn Fs) -> Option<DirSnapshot> {
+    let mut snapshot = DirSnapshot {
+        modified,
+        files: Vec::new(),
+        dirs: Vec::new(),
+        symlinks: Vec::new(),
+        special: Vec::new(),
+    };
+
+    for (name, kind) in fs.read_dir(dir).ok()? {
+        match kind {
+            FileKind::Dir => snapshot.dirs.push(name),
+            FileKind::Symlink => snapshot.symlinks.push(name),
+            FileKind::File => snapshot.files.push(name),
+            FileKind::Special => snapshot.special.push(name),
+            FileKind::Other => (),
+        }
+    }
+    snapshot.files.sort();
+    snapshot.dirs.sort();
+    snapshot.symlinks.sort();
+    snapshot.special.sort();
+    Some(snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::symlink};
+
+    use super::*;
+    use crate::filesystem::RealFs;
+
+    fn paths(entries: Vec<(PathBuf, LinkKind)>) -> Vec<PathBuf> {
+        entries.into_iter().map(|(path, _)| path).collect()
+    }
+
+    #[test]
+    fn reuse_unmodified() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().canonicalize().unwrap();
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join("a/b/file"), "").unwrap();
+
+        // Directories modified just now aren't recorded
+        let mut previous = Snapshot::default();
+        let files = previous.walk(
+            &dir,
+            None,
+            SymlinkPolicy::Follow,
+            false,
+            &mut Report::default(),
+            &RealFs,
+        );
+        assert_eq!(files, vec![(PathBuf::from("a/b/file"), LinkKind::Symlink)]);
+        assert!(previous.dirs.is_empty());
+
+        let past = SystemTime::now() - RACY;
+        for sub in ["", "a", "a/b"] {
+            fs::File::open(dir.join(sub))
+                .and_then(|d| d.set_modified(past))
+                .unwrap();
+        }
+        let files = previous.walk(
+            &dir,
+            None,
+            SymlinkPolicy::Follow,
+            false,
+            &mut Report::default(),
+            &RealFs,
+        );
+        assert_eq!(files, vec![(PathBuf::from("a/b/file"), LinkKind::Symlink)]);
+
+        // A recorded directory that wasn't modified isn't read again
+        let fake = dir.join("a/b");
+        previous.dirs.get_mut(&fake).unwrap().files = vec!["recorded".into()];
+        let mut snapshot = Snapshot::default();
+        assert_eq!(
+            paths(snapshot.walk(
+                &dir,
+                Some(&previous),
+                SymlinkPolicy::Follow,
+                false,
+                &mut Report::default(),
+                &RealFs
+            )),
+            vec![PathBuf::from("a/b/recorded")]
+        );
+
+        // A modified directory is
+        fs::write(dir.join("a/b/new"), "").unwrap();
+        let mut snapshot = Snapshot::default();
+        assert_eq!(
+            paths(snapshot.walk(
+                &dir,
+                Some(&previous),
+                SymlinkPolicy::Follow,
+                false,
+                &mut Report::default(),
+                &RealFs
+            )),
+            vec![PathBuf::from("a/b/file"), PathBuf::from("a/b/new")]
+        );
+        assert_eq!(snapshot.dirs.len(), 2);
+        assert!(!snapshot.dirs.contains_key(&fake));
+    }
+
+    #[test]
+    fn symlink_policies() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().canonicalize().unwrap();
+        fs::create_dir_all(dir.join("repo/dir")).unwrap();
+        fs::write(dir.join("repo/dir/file"), "").unwrap();
+        fs::write(dir.join("outside"), "").unwrap();
+        let repo = dir.join("repo");
+        symlink(dir.join("outside"), repo.join("to_file")).unwrap();
+        symlink("dir", repo.join("to_dir")).unwrap();
+        symlink("..", repo.join("dir/to_parent")).unwrap();
+        symlink("missing", repo.join("dangling")).unwrap();
+
+        let mut report = Report::default();
+        let mut entries = Snapshot::default().walk(
+            &repo,
+            None,
+            SymlinkPolicy::Follow,
+            false,
+            &mut report,
+            &RealFs,
+        );
+        entries.sort_by(|lhs, rhs| lhs.0.cmp(&rhs.0));
+        assert_eq!(
+            entries,
+            vec![
+                (PathBuf::from("dir/file"), LinkKind::Symlink),
+                (PathBuf::from("to_dir/file"), LinkKind::Symlink),
+                (PathBuf::from("to_file"), LinkKind::Symlink),
+            ]
+        );
+        let mut reasons: Vec<SkipReason> = report.skipped.into_iter().map(|s| s.reason).collect();
+        reasons.sort_by_key(|r| r.to_string());
+        assert_eq!(
+            reasons,
+            vec![
+                SkipReason::Dangling,
+                SkipReason::Cycle(repo.clone()),
+                SkipReason::Cycle(repo.clone()),
+            ]
+        );
+
+        let mut entries = Snapshot::default().walk(
+            &repo,
+            None,
+            SymlinkPolicy::Replicate,
+            false,
+            &mut Report::default(),
+            &RealFs,
+        );
+        entries.sort_by(|lhs, rhs| lhs.0.cmp(&rhs.0));
+        assert_eq!(
+            entries,
+            vec![
+                (PathBuf::from("dangling"), LinkKind::Replica),
+                (PathBuf::from("dir/file"), LinkKind::Symlink),
+                (PathBuf::from("dir/to_parent"), LinkKind::Replica),
+                (PathBuf::from("to_dir"), LinkKind::Replica),
+                (PathBuf::from("to_file"), LinkKind::Replica),
+            ]
+        );
+
+        let mut report = Report::default();
+        let entries = Snapshot::default().walk(
+            &repo,
+            None,
+            SymlinkPolicy::Skip,
+            false,
+            &mut report,
+            &RealFs,
+        );
+        assert_eq!(report.skipped.len(), 4);
+        assert_eq!(
+            entries,
+            vec![(PathBuf::from("dir/file"), LinkKind::Symlink)]
+        );
+    }
+}
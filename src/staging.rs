@@ -0,0 +1,125 @@
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::{
+    cache::Cache,
+    filesystem::{Fs, RootedFs},
+    home_dir, pretty_path, Mapping,
+};
+
+/// A directory that is used as `/` later, e.g. the root of a container image. Links are
+/// created below it and point to paths inside of it, so they stay valid once it's used.
+#[derive(Debug, Clone)]
+pub struct Staging {
+    root: PathBuf,
+    /// The home directory inside of the root that `~` stands for, `$HOME` if not set
+    home: Option<PathBuf>,
+}
+
+impl Staging {
+    /// `root` has to be absolute with all links resolved
+    pub fn new(root: PathBuf, home: Option<PathBuf>) -> Staging {
+        Staging { root, home }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The filesystem below the root
+    pub fn fs(&self) -> RootedFs {
+        RootedFs::new(self.root.clone())
+    }
+
+    /// The home directory inside of the root
+    pub fn home(&self) -> PathBuf {
+        let home = self.home.clone();
+        home.or_else(home_dir).unwrap_or_else(|| PathBuf::from("/"))
+    }
+
+    /// The directory the cache is stored in, below the home directory inside of the root
+    pub fn cache_dir(&self) -> PathBuf {
+        self.fs().host_path(&self.home()).join(".cache/george")
+    }
+
+    /// The directory the cache of the system deploy is stored in, below the root
+    pub fn system_cache_dir(&self) -> PathBuf {
+        self.fs().host_path(&Cache::system_dir())
+    }
+
+    /// Where a link named `name` is created inside of the root. Names already below the root are
+    /// made relative to it and names below `$HOME` are moved into the home directory.
+    pub fn name(&self, name: &Path) -> PathBuf {
+        if let Ok(rest) = name.strip_prefix(&self.root) {
+            return Path::new("/").join(rest);
+        }
+        let real_home = home_dir();
+        match (
+            &self.home,
+            real_home.as_deref().and_then(|h| name.strip_prefix(h).ok()),
+        ) {
+            (Some(home), Some(rest)) => home.join(rest),
+            _ => name.to_owned(),
+        }
+    }
+
+    /// The path inside of the root of a target below it, other targets are kept
+    pub fn target(&self, target: &Path) -> PathBuf {
+        match target.strip_prefix(&self.root) {
+            Ok(rest) => Path::new("/").join(rest),
+            Err(_) => target.to_owned(),
+        }
+    }
+
+    /// Moves the link of `mapping` into the root. Its target has to be inside of it as well,
+    /// otherwise it can't be found there and the link is skipped.
+    pub(crate) fn rebase(&self, mapping: &mut Mapping) {
+        if !mapping.target.as_os_str().is_empty() && !mapping.target.starts_with(&self.root) {
+            warn!(
+                "{}: target is outside of {}, skipping the link",
+                mapping.with_source(),
+                pretty_path(&self.root)
+            );
+        }
+
+        mapping.name = self.name(&mapping.name);
+        if !mapping.target.as_os_str().is_empty() {
+            mapping.target = self.target(&mapping.target);
+        }
+        if let Some(origin) = mapping.origin.as_mut() {
+            origin.name = self.name(&origin.name);
+            origin.target = self.target(&origin.target);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rebase_into_root() {
+        let staging = Staging::new(
+            PathBuf::from("/tmp/image"),
+            Some(PathBuf::from("/home/dev")),
+        );
+        let home = home_dir().unwrap_or_else(|| PathBuf::from("/"));
+        let home = home.display();
+
+        let mut mapping = Mapping::new(&format!("{home}/.zshrc"), "/tmp/image/dotfiles/zshrc");
+        staging.rebase(&mut mapping);
+        assert_eq!(mapping.name(), Path::new("/home/dev/.zshrc"));
+        assert_eq!(mapping.target(), Path::new("/dotfiles/zshrc"));
+
+        let mut mapping = Mapping::directory("/tmp/image/etc/george");
+        staging.rebase(&mut mapping);
+        assert_eq!(mapping.name(), Path::new("/etc/george"));
+        assert_eq!(mapping.target(), Path::new(""));
+
+        assert_eq!(
+            staging.cache_dir(),
+            PathBuf::from("/tmp/image/home/dev/.cache/george")
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use std::{fmt::Display, path::PathBuf};
+
+use crate::{
+    cache::Cache,
+    filesystem::Fs,
+    permissions::{self, Drift},
+    pretty_path, secret, LinkKind, Mapping,
+};
+
+/// The state of a cached link on disk
+#[derive(Debug, PartialEq, Eq)]
+pub enum LinkStatus {
+    /// The link exists and points to its target
+    Ok,
+    /// The link doesn't exist anymore
+    Missing,
+    /// The link was replaced by something else, e.g. a regular file or a link to another target
+    Changed(Option<PathBuf>),
+    /// The link exists, but its target doesn't
+    TargetMissing,
+    /// A created directory was replaced by something else
+    NotADirectory,
+    /// A decrypted secret was modified or replaced
+    Modified,
+    /// The link is fine, but the permissions differ from the configured ones
+    Drift(Vec<Drift>),
+}
+
+impl Display for LinkStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkStatus::Ok => write!(f, "ok"),
+            LinkStatus::Missing => write!(f, "missing"),
+            LinkStatus::Changed(Some(path)) => write!(f, "points to {}", pretty_path(path)),
+            LinkStatus::Changed(None) => write!(f, "not a symbolic link"),
+            LinkStatus::TargetMissing => write!(f, "target doesn't exist"),
+            LinkStatus::NotADirectory => write!(f, "not a directory"),
+            LinkStatus::Modified => write!(f, "modified since it was decrypted"),
+            LinkStatus::Drift(drift) => {
+                let drift: Vec<String> = drift.iter().map(|d| d.to_string()).collect();
+                write!(f, "{}", drift.join(", "))
+            }
+        }
+    }
+}
+
+/// The cached links whose target has uncommitted changes, given the `changed` files of the
+/// dotfiles repository
+pub fn uncommitted<'a>(cache: &'a Cache, changed: &[PathBuf], fs: &dyn Fs) -> Vec<&'a Mapping> {
+    let is_changed = |m: &&Mapping| {
+        let target = fs.host_path(m.target());
+        changed.iter().any(|path| path.starts_with(&target))
+    };
+    let links = cache.mappings().iter();
+    links
+        .filter(|m| m.kind() != LinkKind::Directory)
+        .filter(is_changed)
+        .collect()
+}
+
+pub fn link_status(mapping: &Mapping, fs: &dyn Fs) -> LinkStatus {
+    match link_state(mapping, fs) {
+        LinkStatus::Ok => {
+            let drift = permissions::drift(mapping, fs);
+            if drift.is_empty() {
+                LinkStatus::Ok
+            } else {
+                LinkStatus::Drift(drift)
+            }
+        }
+        status => status,
+    }
+}
+
+/// The status of the link itself, ignoring its permissions
+fn link_state(mapping: &Mapping, fs: &dyn Fs) -> LinkStatus {
+    let name = mapping.name();
+    if mapping.kind() == LinkKind::Directory {
+        return match (fs.is_dir(name) && !fs.is_symlink(name), fs.exists(name)) {
+            (true, _) => LinkStatus::Ok,
+            (false, true) => LinkStatus::NotADirectory,
+            (false, false) => LinkStatus::Missing,
+        };
+    }
+
+    if mapping.kind() == LinkKind::Secret {
+        return if !fs.exists(name) {
+            LinkStatus::Missing
+        } else if fs.is_symlink(name) || secret::file_hash(name, fs).as_deref() != mapping.hash() {
+            LinkStatus::Modified
+        } else if !fs.exists(mapping.target()) {
+            LinkStatus::TargetMissing
+        } else {
+            LinkStatus::Ok
+        };
+    }
+
+    if !fs.is_symlink(name) {
+        return if fs.exists(name) {
+            LinkStatus::Changed(None)
+        } else {
+            LinkStatus::Missing
+        };
+    }
+
+    match fs.read_link(name) {
+        Ok(link_target) if mapping.link_content(fs).is_ok_and(|c| c == link_target) => {
+            if fs.exists(name) {
+                LinkStatus::Ok
+            } else {
+                LinkStatus::TargetMissing
+            }
+        }
+        Ok(link_target) => LinkStatus::Changed(Some(link_target)),
+        Err(_) => LinkStatus::Missing,
+    }
+}
+
+/// Returns the status of every cached link, sorted by name
+pub fn status<'a>(cache: &'a Cache, fs: &dyn Fs) -> Vec<(&'a Mapping, LinkStatus)> {
+    let mut links: Vec<&Mapping> = cache.mappings().iter().collect();
+    links.sort_by(|lhs, rhs| lhs.name().cmp(rhs.name()));
+    links.into_iter().map(|m| (m, link_status(m, fs))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::symlink};
+
+    use super::*;
+    use crate::filesystem::RealFs;
+
+    #[test]
+    fn statuses() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+
+        let target = format!("{dir}/target");
+        fs::write(&target, "").unwrap();
+        let other = format!("{dir}/other");
+        fs::write(&other, "").unwrap();
+
+        let ok = Mapping::new(&format!("{dir}/ok"), &target);
+        symlink(ok.target(), ok.name()).unwrap();
+        let missing = Mapping::new(&format!("{dir}/missing"), &target);
+        let file = Mapping::new(&format!("{dir}/file"), &target);
+        fs::write(file.name(), "").unwrap();
+        let changed = Mapping::new(&format!("{dir}/changed"), &target);
+        let other = Mapping::new(&other, &other);
+        symlink(other.target(), changed.name()).unwrap();
+        let dangling = Mapping::new(&format!("{dir}/dangling"), &format!("{dir}/gone"));
+        symlink(dangling.target(), dangling.name()).unwrap();
+
+        assert_eq!(link_status(&ok, &RealFs), LinkStatus::Ok);
+        assert_eq!(link_status(&missing, &RealFs), LinkStatus::Missing);
+        assert_eq!(link_status(&file, &RealFs), LinkStatus::Changed(None));
+        assert_eq!(
+            link_status(&changed, &RealFs),
+            LinkStatus::Changed(Some(other.target().to_owned()))
+        );
+        assert_eq!(link_status(&dangling, &RealFs), LinkStatus::TargetMissing);
+    }
+}
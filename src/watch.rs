@@ -0,0 +1,166 @@
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::Duration,
+};
+
+use log::{error, info};
+use notify::{RecursiveMode, Watcher};
+
+use crate::{
+    cache::Cache,
+    config::Config,
+    deploy::{deploy, DeployOptions},
+    pretty_path, LinkKind, Selection,
+};
+
+/// Changes arriving within this time of each other are applied together
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Deploys the config at `path` and then watches its directory, re-deploying whenever the config
+/// or a target changes. Only the links affected by the changed paths are updated.
+pub fn watch(path: PathBuf, rmdir: bool, jobs: usize) -> Result<(), Box<dyn Error>> {
+    let dotfiles = path.parent().unwrap().to_owned();
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&dotfiles, RecursiveMode::Recursive)?;
+
+    let config = Config::build(path.clone())?;
+    let (mut cache, report) = deploy(
+        Cache::load().unwrap_or_default(),
+        DeployOptions::new(rmdir).with_jobs(jobs),
+        config,
+    );
+    report.log();
+    if cache.is_changed() {
+        cache.save()?;
+    }
+    info!("watching {} for changes", pretty_path(&dotfiles));
+
+    loop {
+        let mut events = vec![rx.recv()?];
+        while let Ok(event) = rx.recv_timeout(DEBOUNCE) {
+            events.push(event);
+        }
+        let mut changed: Vec<PathBuf> = Vec::new();
+        for event in events {
+            match event {
+                Ok(event) => changed.extend(event.paths),
+                Err(e) => error!("failed to watch for changes: {}", e),
+            }
+        }
+        changed.retain(|p| !p.strip_prefix(&dotfiles).is_ok_and(is_git_path));
+        changed.sort();
+        changed.dedup();
+        if changed.is_empty() {
+            continue;
+        }
+
+        let config = match Config::build(path.clone()) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("{}, waiting for further changes", e);
+                continue;
+            }
+        };
+
+        let selection = if changed.contains(&path) {
+            info!("{} changed, deploying everything", pretty_path(&path));
+            Selection::default()
+        } else {
+            match affected(&config, &changed) {
+                Some(selection) => selection,
+                None => continue,
+            }
+        };
+
+        let report;
+        (cache, report) = deploy(
+            cache,
+            DeployOptions::new(rmdir)
+                .with_selection(selection)
+                .with_jobs(jobs),
+            config,
+        );
+        report.log();
+        if cache.is_changed() {
+            cache.save()?;
+        }
+    }
+}
+
+fn is_git_path(path: &Path) -> bool {
+    path.components()
+        .next()
+        .is_some_and(|c| c.as_os_str() == ".git")
+}
+
+/// Selects the links whose targets lie at or below the changed paths, `None` if no mapping is
+/// affected by the changes
+fn affected(config: &Config, changed: &[PathBuf]) -> Option<Selection> {
+    let mut paths = Vec::new();
+    for mapping in config.mappings() {
+        // Directory mappings have no target that could change
+        if mapping.kind() == LinkKind::Directory {
+            continue;
+        }
+        for path in changed {
+            if let Ok(rest) = path.strip_prefix(mapping.target()) {
+                paths.push(mapping.name().join(rest));
+            } else if mapping.target().starts_with(path) {
+                paths.push(mapping.name().to_owned());
+            }
+        }
+    }
+
+    if paths.is_empty() {
+        None
+    } else {
+        Some(Selection::new(paths, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::{Mapping, Origin};
+
+    #[test]
+    fn affected_links() {
+        let dotfiles = env::current_dir().unwrap();
+        let config = Config::parse(
+            "~/.config -> config
+~/.zshrc -> zsh/zshrc",
+            dotfiles.join(".george"),
+        )
+        .unwrap();
+
+        let origin = |line: usize, name: &str, target: &str| Origin {
+            config: dotfiles.join(".george"),
+            line,
+            name: crate::expand_path(name),
+            target: dotfiles.join(target),
+        };
+        let init = Mapping::new("~/.config/nvim/init.lua", "config/nvim/init.lua")
+            .with_origin(Some(origin(1, "~/.config", "config")));
+        let git = Mapping::new("~/.config/git/config", "config/git/config")
+            .with_origin(Some(origin(1, "~/.config", "config")));
+        let zshrc =
+            Mapping::new("~/.zshrc", "zsh/zshrc").with_origin(Some(origin(2, "~/.zshrc", "zsh")));
+
+        let selection = affected(&config, &[dotfiles.join("config/nvim")]).unwrap();
+        assert!(selection.matches(&init));
+        assert!(!selection.matches(&git));
+        assert!(!selection.matches(&zshrc));
+
+        // Removing the directory containing a target affects the whole mapping
+        let selection = affected(&config, &[dotfiles.join("zsh")]).unwrap();
+        assert!(!selection.matches(&init));
+        assert!(selection.matches(&zshrc));
+
+        assert!(affected(&config, &[dotfiles.join("README.md")]).is_none());
+    }
+}